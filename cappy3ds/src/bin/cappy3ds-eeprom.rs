@@ -0,0 +1,81 @@
+use std::env;
+use std::process;
+
+use bytes::BytesMut;
+use cappy3ds::{Cappy3ds, EepromDump};
+
+const USAGE: &str = "usage:
+  cappy3ds-eeprom backup <file>
+  cappy3ds-eeprom verify <file>
+  cappy3ds-eeprom restore <file> --i-have-a-backup <backup>
+
+restore only writes if the card still matches <backup>, make one with backup first";
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    let result = match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        ["backup", path] => backup(path),
+        ["verify", path] => verify(path),
+        ["restore", path, "--i-have-a-backup", backup] => restore(path, backup),
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+
+    if let Err(err) = result {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
+
+// the eeprom tools never capture, frames are thrown away
+type IgnoreFrames = fn(&[i16], BytesMut, BytesMut);
+
+fn connect() -> Result<Cappy3ds<IgnoreFrames>, cappy3ds::Error> {
+    let mut cappy3ds = Cappy3ds::new((|_, _, _| {}) as IgnoreFrames);
+    let info = cappy3ds.connect()?;
+    println!("Connected to {}", info);
+
+    Ok(cappy3ds)
+}
+
+fn backup(path: &str) -> Result<(), cappy3ds::Error> {
+    let dump = connect()?.backup_eeprom()?;
+    dump.save(path)?;
+
+    println!("Saved eeprom to {} (crc32 {:08x})", path, dump.checksum());
+    Ok(())
+}
+
+fn verify(path: &str) -> Result<(), cappy3ds::Error> {
+    let dump = EepromDump::load(path)?;
+
+    println!("{} is valid (crc32 {:08x})", path, dump.checksum());
+    println!("{:02X?}", dump.data());
+    Ok(())
+}
+
+fn restore(path: &str, backup_path: &str) -> Result<(), cappy3ds::Error> {
+    // refuse before touching the card if either file is damaged
+    let dump = EepromDump::load(path)?;
+    let backup = EepromDump::load(backup_path)?;
+
+    let cappy3ds = connect()?;
+
+    let current = cappy3ds.backup_eeprom()?;
+    if current != backup {
+        eprintln!(
+            "the card's eeprom (crc32 {:08x}) does not match {} (crc32 {:08x})",
+            current.checksum(),
+            backup_path,
+            backup.checksum()
+        );
+    }
+
+    cappy3ds.restore_eeprom(&dump, &backup)?;
+
+    println!("Restored eeprom from {} (crc32 {:08x})", path, dump.checksum());
+    Ok(())
+}
@@ -0,0 +1,67 @@
+use crate::ColorDepth;
+
+/// Size of one screen as delivered to the data callback, in RGBA pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScreenGeometry {
+    /// Pixels per row of the buffer
+    pub width: u32,
+    /// Rows in the buffer
+    pub height: u32,
+    /// The buffer holds the screen turned 90 degrees, the way 3DS LCDs are scanned out
+    pub rotated: bool,
+}
+
+impl ScreenGeometry {
+    /// Bytes in one complete RGBA buffer for this screen.
+    pub fn buffer_len(&self) -> usize {
+        self.width as usize * self.height as usize * 4
+    }
+
+    /// Width and height as seen by the player, with rotation undone.
+    pub fn display_size(&self) -> (u32, u32) {
+        if self.rotated {
+            (self.height, self.width)
+        } else {
+            (self.width, self.height)
+        }
+    }
+}
+
+/// Format of the samples passed to the data callback.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AudioFormat {
+    pub sample_rate: u32,
+    /// Interleaved channels, 2 for stereo
+    pub channels: u16,
+}
+
+/// What a backend delivers, so frontends can size textures and audio without guessing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Capabilities {
+    pub upper_screen: ScreenGeometry,
+    pub lower_screen: ScreenGeometry,
+    /// Formats that can be picked with `ConnectConfig::color_depth`
+    pub color_depths: &'static [ColorDepth],
+    /// `None` if the card delivers no decoded sound, the callback's audio slice is then not
+    /// samples in this format
+    pub audio: Option<AudioFormat>,
+    /// True if the upper screen can be captured as a left/right pair
+    pub stereoscopic: bool,
+    /// Native refresh rate of the console in frames per second
+    pub frame_rate: f32,
+}
+
+// shared by every 3DS card: 400x240 upper and 320x240 lower LCD, sent a column at a time
+pub(crate) const UPPER_3DS: ScreenGeometry = ScreenGeometry {
+    width: 240,
+    height: 400,
+    rotated: true,
+};
+
+pub(crate) const LOWER_3DS: ScreenGeometry = ScreenGeometry {
+    width: 240,
+    height: 320,
+    rotated: true,
+};
+
+pub(crate) const FRAME_RATE_3DS: f32 = 59.83;
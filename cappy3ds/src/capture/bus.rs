@@ -0,0 +1,144 @@
+use rusb::{Context, Device, DeviceDescriptor, UsbContext};
+
+use super::transport::Transport;
+use crate::Error;
+
+/// An open device as handed to a backend.
+pub type UsbHandle = Box<dyn Transport + Send>;
+
+/// Descriptor details read when a device is opened.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct UsbDescription {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    /// `bcdDevice` as (major, minor, sub_minor)
+    pub device_version: (u8, u8, u8),
+    /// Product string, if the device has one
+    pub product: Option<String>,
+    /// Serial number string, if the device has one
+    pub serial: Option<String>,
+}
+
+/// A device opened through a `UsbBus`.
+pub struct OpenDevice {
+    pub handle: UsbHandle,
+    pub description: UsbDescription,
+}
+
+/// A device as listed by `UsbBus::devices`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct UsbDevice {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub bus_number: u8,
+    /// Address on the bus, a device that re-enumerates comes back with a new one
+    pub address: u8,
+}
+
+/// Which device `UsbBus::open` picks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UsbSelector {
+    /// The first device with this VID/PID
+    Id { vendor_id: u16, product_id: u16 },
+    /// Exactly this device, as long as it is still plugged in with the same ids
+    Device(UsbDevice),
+}
+
+impl UsbSelector {
+    pub fn id(vendor_id: u16, product_id: u16) -> Self {
+        Self::Id {
+            vendor_id,
+            product_id,
+        }
+    }
+
+    pub fn matches(&self, device: &UsbDevice) -> bool {
+        match self {
+            Self::Id {
+                vendor_id,
+                product_id,
+            } => (device.vendor_id, device.product_id) == (*vendor_id, *product_id),
+            Self::Device(selected) => selected == device,
+        }
+    }
+}
+
+/// Where backends find and open their devices.
+///
+/// `RusbBus` is the real thing, tests plug in a simulated card instead.
+pub trait UsbBus: Send + Sync {
+    /// Everything currently plugged in.
+    fn devices(&self) -> Result<Vec<UsbDevice>, Error>;
+
+    /// Opens the device `selector` picks, `None` if there is none.
+    fn open(&self, selector: UsbSelector) -> Result<Option<OpenDevice>, Error>;
+}
+
+/// The USB bus of this machine, through libusb.
+pub struct RusbBus {
+    context: Context,
+}
+
+impl RusbBus {
+    pub fn new() -> Result<Self, Error> {
+        Ok(Self {
+            context: Context::new().map_err(Error::UsbInit)?,
+        })
+    }
+}
+
+impl UsbBus for RusbBus {
+    fn devices(&self) -> Result<Vec<UsbDevice>, Error> {
+        let devices = self.context.devices().map_err(Error::UsbInit)?;
+
+        Ok(devices
+            .iter()
+            .filter_map(|device| {
+                let descriptor = device.device_descriptor().ok()?;
+                Some(listed(&device, &descriptor))
+            })
+            .collect())
+    }
+
+    fn open(&self, selector: UsbSelector) -> Result<Option<OpenDevice>, Error> {
+        let devices = self.context.devices().map_err(Error::UsbInit)?;
+
+        for device in devices.iter() {
+            let descriptor = match device.device_descriptor() {
+                Ok(d) => d,
+                Err(_) => continue,
+            };
+
+            if !selector.matches(&listed(&device, &descriptor)) {
+                continue;
+            }
+
+            let handle = device.open().map_err(Error::from_open)?;
+            let version = descriptor.device_version();
+
+            let description = UsbDescription {
+                vendor_id: descriptor.vendor_id(),
+                product_id: descriptor.product_id(),
+                device_version: (version.major(), version.minor(), version.sub_minor()),
+                product: handle.read_product_string_ascii(&descriptor).ok(),
+                serial: handle.read_serial_number_string_ascii(&descriptor).ok(),
+            };
+
+            return Ok(Some(OpenDevice {
+                handle: Box::new(handle),
+                description,
+            }));
+        }
+
+        Ok(None)
+    }
+}
+
+fn listed(device: &Device<Context>, descriptor: &DeviceDescriptor) -> UsbDevice {
+    UsbDevice {
+        vendor_id: descriptor.vendor_id(),
+        product_id: descriptor.product_id(),
+        bus_number: device.bus_number(),
+        address: device.address(),
+    }
+}
@@ -0,0 +1,364 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use super::ColorDepth;
+use crate::Error;
+
+/// Environment variable naming a TOML file with extra devices, see `DeviceTable::with_env`.
+pub const DEVICE_TABLE_ENV: &str = "CAPPY3DS_DEVICE_TABLE";
+
+// the cards we ship support for, same format as user files
+const BUILTIN: &str = include_str!("devices.toml");
+
+/// USB vendor and product id pair as written in the device table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct UsbId {
+    pub vendor_id: u16,
+    pub product_id: u16,
+}
+
+/// Endpoint addresses a backend talks to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Endpoints {
+    /// Bulk OUT endpoint commands are written to
+    pub command: u8,
+    /// Bulk IN endpoint answers to commands arrive on
+    pub response: u8,
+    /// Bulk IN endpoint frames are streamed on
+    pub data: u8,
+}
+
+impl Default for Endpoints {
+    fn default() -> Self {
+        Self {
+            command: 0x01,
+            response: 0x81,
+            data: 0x82,
+        }
+    }
+}
+
+/// Things a particular model needs that its backend can't work out by itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Quirks {
+    /// The card first shows up as a bare FX2 with this id and needs its firmware uploaded
+    pub fx2_upload: Option<UsbId>,
+    /// Milliseconds to leave the card alone after configuring it before using it
+    pub post_config_delay_ms: u64,
+}
+
+impl Quirks {
+    pub fn post_config_delay(&self) -> Duration {
+        Duration::from_millis(self.post_config_delay_ms)
+    }
+}
+
+/// One capture card model and how to drive it.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceProfile {
+    /// Name shown in logs
+    pub name: String,
+    /// Name of the backend that drives it, as listed by `backends()`
+    pub backend: String,
+    /// VID once the card is ready to capture
+    pub vendor_id: u16,
+    /// PID once the card is ready to capture
+    pub product_id: u16,
+    /// Only match cards whose product string starts with this
+    #[serde(default)]
+    pub product: Option<String>,
+    /// Interfaces claimed while the card is in use
+    #[serde(default = "default_interfaces")]
+    pub interfaces: Vec<u8>,
+    #[serde(default)]
+    pub endpoints: Endpoints,
+    /// Pixel format used unless `ConnectConfig::color_depth` picks one
+    #[serde(default)]
+    pub color_depth: ColorDepth,
+    #[serde(default)]
+    pub quirks: Quirks,
+}
+
+fn default_interfaces() -> Vec<u8> {
+    vec![0]
+}
+
+impl DeviceProfile {
+    /// True if `vendor_id`/`product_id` is this card, either ready or waiting for firmware.
+    pub fn matches_id(&self, vendor_id: u16, product_id: u16) -> bool {
+        let id = UsbId {
+            vendor_id,
+            product_id,
+        };
+
+        self.id() == id || self.quirks.fx2_upload == Some(id)
+    }
+
+    /// Every id the card can show up as.
+    pub fn device_ids(&self) -> impl Iterator<Item = (u16, u16)> + '_ {
+        self.quirks
+            .fx2_upload
+            .into_iter()
+            .chain(Some(self.id()))
+            .map(|id| (id.vendor_id, id.product_id))
+    }
+
+    // the product string only exists once the card runs its own firmware
+    fn matches(&self, vendor_id: u16, product_id: u16, product: Option<&str>) -> bool {
+        let id = UsbId {
+            vendor_id,
+            product_id,
+        };
+
+        if self.quirks.fx2_upload == Some(id) {
+            return true;
+        }
+
+        self.id() == id
+            && match (&self.product, product) {
+                (Some(expected), Some(product)) => product.starts_with(expected.as_str()),
+                (Some(_), None) => false,
+                (None, _) => true,
+            }
+    }
+
+    fn id(&self) -> UsbId {
+        UsbId {
+            vendor_id: self.vendor_id,
+            product_id: self.product_id,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct DeviceFile {
+    #[serde(default)]
+    device: Vec<DeviceProfile>,
+}
+
+/// Every card model the library will try to connect to, in the order they are tried.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceTable {
+    devices: Vec<DeviceProfile>,
+}
+
+impl Default for DeviceTable {
+    fn default() -> Self {
+        Self::builtin()
+    }
+}
+
+impl DeviceTable {
+    /// The cards that ship with the library.
+    pub fn builtin() -> Self {
+        Self::from_toml(BUILTIN).expect("built in device table is valid")
+    }
+
+    /// Parses a table written like `src/capture/devices.toml`.
+    pub fn from_toml(text: &str) -> Result<Self, Error> {
+        let file: DeviceFile =
+            toml::from_str(text).map_err(|err| Error::InvalidDeviceTable(err.to_string()))?;
+
+        Ok(Self {
+            devices: file.device,
+        })
+    }
+
+    /// Reads a table from a TOML file.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let invalid =
+            |reason: String| Error::InvalidDeviceTable(format!("{}: {}", path.display(), reason));
+
+        let text = fs::read_to_string(path).map_err(|err| invalid(err.to_string()))?;
+
+        Self::from_toml(&text).map_err(|err| invalid(err.to_string()))
+    }
+
+    /// Adds the devices from `other`, they are tried before the ones already in the table.
+    pub fn extend(&mut self, other: DeviceTable) {
+        self.devices.splice(0..0, other.devices);
+    }
+
+    /// This table with the file named in `CAPPY3DS_DEVICE_TABLE` added, if it is set.
+    pub fn with_env(&self) -> Result<Self, Error> {
+        let mut table = self.clone();
+
+        if let Some(path) = env::var_os(DEVICE_TABLE_ENV) {
+            table.extend(Self::load(path)?);
+        }
+
+        Ok(table)
+    }
+
+    pub fn devices(&self) -> &[DeviceProfile] {
+        &self.devices
+    }
+
+    /// First device matching the ids and, where an entry asks for one, the product string.
+    pub fn find(
+        &self,
+        vendor_id: u16,
+        product_id: u16,
+        product: Option<&str>,
+    ) -> Option<&DeviceProfile> {
+        self.devices
+            .iter()
+            .find(|device| device.matches(vendor_id, product_id, product))
+    }
+
+    /// True if telling devices with this id apart needs their product string.
+    pub fn needs_product(&self, vendor_id: u16, product_id: u16) -> bool {
+        self.devices.iter().any(|device| {
+            device.product.is_some()
+                && device.vendor_id == vendor_id
+                && device.product_id == product_id
+        })
+    }
+
+    /// Every VID/PID pair in the table, including pre-firmware ones.
+    pub fn device_ids(&self) -> impl Iterator<Item = (u16, u16)> + '_ {
+        self.devices.iter().flat_map(|device| device.device_ids())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const USER_TABLE: &str = r#"
+        [[device]]
+        name = "Katsukity prototype"
+        backend = "Katsukity"
+        vendor_id = 0x0752
+        product_id = 0xf2c0
+        color_depth = "rgb888"
+
+        [device.quirks]
+        fx2_upload = { vendor_id = 0x0752, product_id = 0x8613 }
+        post_config_delay_ms = 250
+    "#;
+
+    fn invalid(text: &str) -> bool {
+        matches!(
+            DeviceTable::from_toml(text),
+            Err(Error::InvalidDeviceTable(_))
+        )
+    }
+
+    #[test]
+    fn builtin_table_parses() {
+        let table = DeviceTable::builtin();
+        let names: Vec<_> = table
+            .devices()
+            .iter()
+            .map(|device| device.name.as_str())
+            .collect();
+        assert_eq!(
+            names,
+            ["Katsukity New 3DS XL", "Loopy New 3DS XL", "Loopy DS"]
+        );
+
+        let katsukity = &table.devices()[0];
+        assert_eq!(katsukity.interfaces, [0]);
+        assert_eq!(katsukity.endpoints, Endpoints::default());
+        assert_eq!(
+            katsukity.quirks.fx2_upload,
+            Some(UsbId {
+                vendor_id: 0x0752,
+                product_id: 0x8613
+            })
+        );
+
+        let ids: Vec<_> = table.device_ids().collect();
+        assert_eq!(
+            ids,
+            [
+                (0x0752, 0x8613),
+                (0x0752, 0xf2c0),
+                (0x0403, 0x601e),
+                (0x16d0, 0x0647)
+            ]
+        );
+    }
+
+    #[test]
+    fn find_matches_ids_and_product() {
+        let table = DeviceTable::builtin();
+
+        // a bare FX2 has no product string yet
+        assert_eq!(
+            table.find(0x0752, 0x8613, None).unwrap().backend,
+            "Katsukity"
+        );
+        assert_eq!(
+            table.find(0x0752, 0xf2c0, None).unwrap().backend,
+            "Katsukity"
+        );
+
+        assert!(table.needs_product(0x0403, 0x601e));
+        assert!(!table.needs_product(0x16d0, 0x0647));
+        assert_eq!(
+            table
+                .find(0x0403, 0x601e, Some("N3DSXL.2"))
+                .unwrap()
+                .backend,
+            "Loopy N3DSXL"
+        );
+        assert_eq!(table.find(0x0403, 0x601e, Some("FT601 USB3")), None);
+        assert_eq!(table.find(0x0403, 0x601e, None), None);
+
+        assert_eq!(table.find(0x1234, 0x5678, None), None);
+    }
+
+    #[test]
+    fn user_entries_override_builtin_ones() {
+        let mut table = DeviceTable::builtin();
+        table.extend(DeviceTable::from_toml(USER_TABLE).unwrap());
+
+        let device = table.find(0x0752, 0xf2c0, None).unwrap();
+        assert_eq!(device.name, "Katsukity prototype");
+        assert_eq!(device.color_depth, ColorDepth::Rgb888);
+        assert_eq!(
+            device.quirks.post_config_delay(),
+            Duration::from_millis(250)
+        );
+
+        // the built in entry is still there behind it
+        assert_eq!(table.devices().len(), 4);
+        assert_eq!(table.devices()[1].name, "Katsukity New 3DS XL");
+    }
+
+    #[test]
+    fn malformed_entries_are_rejected() {
+        let base = USER_TABLE.replace("post_config_delay_ms = 250", "");
+
+        assert!(invalid(&format!("{}post_config_delay_ms = \"soon\"", base)));
+        assert!(invalid(&format!("{}fx2_uplaod = true", base)));
+        assert!(invalid(&USER_TABLE.replace("color_depth", "colour_depth")));
+        assert!(invalid(&base.replace(
+            "fx2_upload = { vendor_id = 0x0752, product_id = 0x8613 }",
+            "fx2_upload = 5"
+        )));
+        assert!(invalid(&USER_TABLE.replace("vendor_id = 0x0752\n", "")));
+        assert!(invalid(&USER_TABLE.replace("\"rgb888\"", "\"rgb444\"")));
+        assert!(invalid("[[device]"));
+    }
+
+    #[test]
+    fn load_errors_name_the_file() {
+        let result = DeviceTable::load("no/such/devices.toml");
+
+        assert!(matches!(
+            result,
+            Err(Error::InvalidDeviceTable(reason)) if reason.starts_with("no/such/devices.toml: ")
+        ));
+    }
+}
@@ -0,0 +1,382 @@
+use crate::Error;
+
+/// A block of bytes to be written to FX2 memory at `address`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub address: u16,
+    pub data: Vec<u8>,
+}
+
+/// An FX2 firmware image as a list of memory segments.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct FirmwareImage {
+    pub segments: Vec<Segment>,
+}
+
+impl FirmwareImage {
+    /// Builds an image from explicit segments.
+    pub fn from_segments(segments: Vec<Segment>) -> Self {
+        Self { segments }
+    }
+
+    /// A flat binary that is loaded as is starting at `address`.
+    pub fn from_binary(data: &[u8], address: u16) -> Result<Self, Error> {
+        if address as usize + data.len() > 0x10000 {
+            return Err(Error::InvalidFirmware("binary does not fit in 64k".to_string()));
+        }
+
+        Ok(Self {
+            segments: vec![Segment {
+                address,
+                data: data.to_vec(),
+            }],
+        })
+    }
+
+    /// Parses an Intel HEX file, merging consecutive records into segments.
+    pub fn from_intel_hex(text: &str) -> Result<Self, Error> {
+        let mut image = Self::default();
+        let mut upper_address = 0u32;
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let invalid = |reason: &str| {
+                Error::InvalidFirmware(format!("line {}: {}", number + 1, reason))
+            };
+
+            let record = line
+                .strip_prefix(':')
+                .ok_or_else(|| invalid("missing ':'"))
+                .and_then(|record| hex::decode(record).map_err(|_| invalid("bad hex digits")))?;
+
+            if record.len() < 5 || record.len() != record[0] as usize + 5 {
+                return Err(invalid("bad record length"));
+            }
+
+            if record.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0 {
+                return Err(invalid("bad checksum"));
+            }
+
+            let address = u16::from_be_bytes([record[1], record[2]]);
+            let data = &record[4..record.len() - 1];
+
+            match record[3] {
+                // data
+                0x00 => {
+                    let address = upper_address + address as u32;
+                    if address + data.len() as u32 > 0x10000 {
+                        return Err(invalid("data above 64k"));
+                    }
+                    image.push(address as u16, data);
+                }
+                // end of file
+                0x01 => break,
+                // extended segment address
+                0x02 if data.len() == 2 => {
+                    upper_address = (u16::from_be_bytes([data[0], data[1]]) as u32) << 4;
+                }
+                // extended linear address
+                0x04 if data.len() == 2 => {
+                    upper_address = (u16::from_be_bytes([data[0], data[1]]) as u32) << 16;
+                }
+                // start addresses mean nothing to the FX2
+                0x03 | 0x05 => {}
+                _ => return Err(invalid("unsupported record type")),
+            }
+        }
+
+        Ok(image)
+    }
+
+    /// Parses a Cypress "C2" IIC EEPROM image.
+    ///
+    /// After the 8 byte header every record is a 10 bit length (top bit marks the last
+    /// record), a big endian load address and the data.
+    pub fn from_iic(data: &[u8]) -> Result<Self, Error> {
+        let invalid = |reason: &str| Error::InvalidFirmware(format!("iic: {}", reason));
+
+        if data.len() < 8 || data[0] != 0xc2 {
+            return Err(invalid("missing C2 header"));
+        }
+
+        let mut image = Self::default();
+        let mut pos = 8;
+
+        loop {
+            let header = data
+                .get(pos..pos + 4)
+                .ok_or_else(|| invalid("truncated record header"))?;
+            let last = header[0] & 0x80 != 0;
+            let len = ((header[0] as usize & 0x03) << 8) | header[1] as usize;
+            let address = u16::from_be_bytes([header[2], header[3]]);
+            pos += 4;
+
+            if address as usize + len > 0x10000 {
+                return Err(invalid("data above 64k"));
+            }
+
+            let record = data
+                .get(pos..pos + len)
+                .ok_or_else(|| invalid("truncated record"))?;
+            pos += len;
+
+            image.push(address, record);
+
+            if last {
+                break;
+            }
+        }
+
+        Ok(image)
+    }
+
+    /// Picks the format from the contents: Intel HEX text, a C2 IIC image, or a flat
+    /// binary loaded at address 0.
+    pub fn parse(data: &[u8]) -> Result<Self, Error> {
+        match data.first() {
+            Some(b':') => match std::str::from_utf8(data) {
+                Ok(text) => Self::from_intel_hex(text),
+                Err(_) => Err(Error::InvalidFirmware("hex file is not text".to_string())),
+            },
+            Some(0xc2) => Self::from_iic(data),
+            _ => Self::from_binary(data, 0),
+        }
+    }
+
+    fn push(&mut self, address: u16, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+
+        if let Some(last) = self.segments.last_mut() {
+            if last.address as usize + last.data.len() == address as usize {
+                last.data.extend_from_slice(data);
+                return;
+            }
+        }
+
+        self.segments.push(Segment {
+            address,
+            data: data.to_vec(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // one Intel HEX record with its checksum
+    fn record(kind: u8, address: u16, data: &[u8]) -> String {
+        let mut bytes = vec![data.len() as u8];
+        bytes.extend_from_slice(&address.to_be_bytes());
+        bytes.push(kind);
+        bytes.extend_from_slice(data);
+        let sum = bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        bytes.push(sum.wrapping_neg());
+
+        format!(":{}", hex::encode_upper(bytes))
+    }
+
+    fn hex(records: &[String]) -> String {
+        records.join("\n")
+    }
+
+    fn invalid(result: Result<FirmwareImage, Error>, reason: &str) -> bool {
+        matches!(result, Err(Error::InvalidFirmware(message)) if message.contains(reason))
+    }
+
+    #[test]
+    fn intel_hex_merges_consecutive_records() {
+        let image = FirmwareImage::from_intel_hex(&hex(&[
+            record(0x00, 0x0000, &[0x02, 0x00, 0x80]),
+            record(0x00, 0x0003, &[0x12, 0x34]),
+            record(0x00, 0x0080, &[0x56]),
+            record(0x01, 0x0000, &[]),
+        ]))
+        .unwrap();
+
+        assert_eq!(
+            image.segments,
+            vec![
+                Segment {
+                    address: 0x0000,
+                    data: vec![0x02, 0x00, 0x80, 0x12, 0x34],
+                },
+                Segment {
+                    address: 0x0080,
+                    data: vec![0x56],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn intel_hex_rejects_bad_checksums() {
+        // the checksum should be FB
+        assert!(invalid(
+            FirmwareImage::from_intel_hex(":03000000020080FA"),
+            "line 1: bad checksum"
+        ));
+        assert!(invalid(
+            FirmwareImage::from_intel_hex(":0300000002"),
+            "bad record length"
+        ));
+        assert!(invalid(
+            FirmwareImage::from_intel_hex("03000000020080FB"),
+            "missing ':'"
+        ));
+    }
+
+    #[test]
+    fn intel_hex_applies_extended_addresses() {
+        let image = FirmwareImage::from_intel_hex(&hex(&[
+            // segment 0x0100 puts the data at 0x1000 up
+            record(0x02, 0x0000, &[0x01, 0x00]),
+            record(0x00, 0x0010, &[0xaa]),
+            record(0x04, 0x0000, &[0x00, 0x00]),
+            record(0x00, 0x0020, &[0xbb]),
+            record(0x03, 0x0000, &[0x00, 0x00, 0x00, 0x00]),
+        ]))
+        .unwrap();
+
+        assert_eq!(
+            image.segments,
+            vec![
+                Segment {
+                    address: 0x1010,
+                    data: vec![0xaa],
+                },
+                Segment {
+                    address: 0x0020,
+                    data: vec![0xbb],
+                },
+            ]
+        );
+
+        // anything past 64k can't be loaded into the FX2
+        assert!(invalid(
+            FirmwareImage::from_intel_hex(&hex(&[
+                record(0x04, 0x0000, &[0x00, 0x01]),
+                record(0x00, 0x0000, &[0xcc]),
+            ])),
+            "data above 64k"
+        ));
+    }
+
+    #[test]
+    fn intel_hex_stops_at_end_of_file() {
+        let image = FirmwareImage::from_intel_hex(&hex(&[
+            record(0x00, 0x0000, &[0x01]),
+            record(0x01, 0x0000, &[]),
+            "trailing junk".to_string(),
+        ]))
+        .unwrap();
+
+        assert_eq!(
+            image.segments,
+            vec![Segment {
+                address: 0x0000,
+                data: vec![0x01],
+            }]
+        );
+    }
+
+    // C2 header: VID, PID, DID and a config byte
+    const IIC_HEADER: [u8; 8] = [0xc2, 0x52, 0x07, 0x13, 0x86, 0x00, 0x00, 0x00];
+
+    #[test]
+    fn iic_reads_every_record() {
+        let mut data = IIC_HEADER.to_vec();
+        data.extend_from_slice(&[0x00, 0x03, 0x00, 0x00, 0x02, 0x00, 0x80]);
+        data.extend_from_slice(&[0x00, 0x01, 0x00, 0x80, 0x12]);
+        // the last record, here the usual CPUCS write that starts the CPU
+        data.extend_from_slice(&[0x80, 0x01, 0xe6, 0x00, 0x00]);
+
+        let image = FirmwareImage::from_iic(&data).unwrap();
+
+        assert_eq!(
+            image.segments,
+            vec![
+                Segment {
+                    address: 0x0000,
+                    data: vec![0x02, 0x00, 0x80],
+                },
+                Segment {
+                    address: 0x0080,
+                    data: vec![0x12],
+                },
+                Segment {
+                    address: 0xe600,
+                    data: vec![0x00],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn iic_rejects_truncated_input() {
+        assert!(invalid(
+            FirmwareImage::from_iic(&[0xc2, 0x52]),
+            "missing C2 header"
+        ));
+
+        let mut data = IIC_HEADER.to_vec();
+        data.extend_from_slice(&[0x00, 0x04, 0x00, 0x00, 0x02, 0x00]);
+        assert!(invalid(FirmwareImage::from_iic(&data), "truncated record"));
+
+        // no record is marked as the last one
+        let mut data = IIC_HEADER.to_vec();
+        data.extend_from_slice(&[0x00, 0x01, 0x00, 0x00, 0x02]);
+        assert!(invalid(
+            FirmwareImage::from_iic(&data),
+            "truncated record header"
+        ));
+    }
+
+    #[test]
+    fn iic_rejects_data_above_64k() {
+        let mut data = IIC_HEADER.to_vec();
+        data.extend_from_slice(&[0x80, 0x02, 0xff, 0xff, 0x01, 0x02]);
+        assert!(invalid(FirmwareImage::from_iic(&data), "data above 64k"));
+
+        // right up to the top is fine
+        let mut data = IIC_HEADER.to_vec();
+        data.extend_from_slice(&[0x80, 0x01, 0xff, 0xff, 0x01]);
+        assert_eq!(
+            FirmwareImage::from_iic(&data).unwrap().segments[0].address,
+            0xffff
+        );
+    }
+
+    #[test]
+    fn parse_detects_the_format() {
+        let text = hex(&[record(0x00, 0x0010, &[0x01]), record(0x01, 0x0000, &[])]);
+        assert_eq!(
+            FirmwareImage::parse(text.as_bytes()).unwrap().segments[0].address,
+            0x0010
+        );
+
+        let mut iic = IIC_HEADER.to_vec();
+        iic.extend_from_slice(&[0x80, 0x01, 0x00, 0x20, 0x01]);
+        assert_eq!(
+            FirmwareImage::parse(&iic).unwrap().segments[0].address,
+            0x0020
+        );
+
+        let binary = FirmwareImage::parse(&[0x02, 0x00, 0x00]).unwrap();
+        assert_eq!(
+            binary.segments,
+            vec![Segment {
+                address: 0x0000,
+                data: vec![0x02, 0x00, 0x00],
+            }]
+        );
+
+        assert!(invalid(FirmwareImage::parse(&[b':', 0xff]), "not text"));
+    }
+}
@@ -0,0 +1,220 @@
+use std::time::Duration;
+
+use rusb::{Direction, Recipient, RequestType};
+
+use super::transport::Transport;
+use crate::Error;
+
+mod firmware;
+
+pub use firmware::{FirmwareImage, Segment};
+
+// "firmware load" vendor request handled by the FX2 boot ROM, reads or writes RAM
+pub(crate) const FIRMWARE_LOAD: u8 = 0xa0;
+// CPU control and status register, bit 0 holds the 8051 in reset
+pub(crate) const CPUCS: u16 = 0xe600;
+// largest payload we send in one control transfer
+const MAX_CHUNK: usize = 1023;
+
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Holds the 8051 in reset, writes every segment of `image`, reads it back and lets the CPU run.
+///
+/// Writes to CPUCS inside the image are skipped, the reset is handled here. If the readback
+/// differs the CPU is left in reset so a corrupt firmware never starts.
+pub(crate) fn load<H: Transport + ?Sized>(handle: &H, image: &FirmwareImage) -> Result<(), Error> {
+    set_reset(handle, true)?;
+    log::debug!("FX2 held in reset for programming");
+
+    for segment in writable_segments(image) {
+        for (address, chunk) in chunks(&segment) {
+            write_ram(handle, address, chunk)?;
+        }
+    }
+
+    for segment in writable_segments(image) {
+        for (address, chunk) in chunks(&segment) {
+            let mut readback = vec![0u8; chunk.len()];
+            read_ram(handle, address, &mut readback)?;
+
+            if let Some(offset) = readback.iter().zip(chunk).position(|(a, b)| a != b) {
+                return Err(Error::FirmwareVerify(offset_address(address, offset)?));
+            }
+        }
+    }
+
+    log::debug!("FX2 firmware written and verified");
+
+    set_reset(handle, false)?;
+    log::debug!("FX2 released from reset for re-enumeration");
+
+    Ok(())
+}
+
+fn set_reset<H: Transport + ?Sized>(handle: &H, reset: bool) -> Result<(), Error> {
+    write_ram(handle, CPUCS, &[reset as u8])
+}
+
+fn write_ram<H: Transport + ?Sized>(handle: &H, address: u16, data: &[u8]) -> Result<(), Error> {
+    let request_type = rusb::request_type(Direction::Out, RequestType::Vendor, Recipient::Device);
+
+    handle
+        .write_control(request_type, FIRMWARE_LOAD, address, 0, data, TIMEOUT)
+        .map_err(Error::FirmwareUpload)?;
+
+    Ok(())
+}
+
+fn read_ram<H: Transport + ?Sized>(
+    handle: &H,
+    address: u16,
+    data: &mut [u8],
+) -> Result<(), Error> {
+    let request_type = rusb::request_type(Direction::In, RequestType::Vendor, Recipient::Device);
+
+    let read = handle
+        .read_control(request_type, FIRMWARE_LOAD, address, 0, data, TIMEOUT)
+        .map_err(Error::FirmwareUpload)?;
+
+    if read != data.len() {
+        return Err(Error::FirmwareVerify(offset_address(address, read)?));
+    }
+
+    Ok(())
+}
+
+// address of the byte `offset` into a chunk at `address`, images are checked to stay below 64k
+// but one built from raw segments might not
+fn offset_address(address: u16, offset: usize) -> Result<u16, Error> {
+    u16::try_from(offset)
+        .ok()
+        .and_then(|offset| address.checked_add(offset))
+        .ok_or_else(|| Error::InvalidFirmware("segment above 64k".to_string()))
+}
+
+// segments with any CPUCS bytes cut out
+fn writable_segments(image: &FirmwareImage) -> impl Iterator<Item = Segment> + '_ {
+    image.segments.iter().flat_map(|segment| {
+        let start = segment.address as usize;
+        let end = start + segment.data.len();
+        let cpucs = CPUCS as usize;
+
+        if cpucs < start || cpucs >= end {
+            return vec![segment.clone()];
+        }
+
+        let split = cpucs - start;
+        let mut parts = Vec::new();
+        if split > 0 {
+            parts.push(Segment {
+                address: segment.address,
+                data: segment.data[..split].to_vec(),
+            });
+        }
+        if split + 1 < segment.data.len() {
+            parts.push(Segment {
+                address: CPUCS + 1,
+                data: segment.data[split + 1..].to_vec(),
+            });
+        }
+        parts
+    })
+}
+
+fn chunks(segment: &Segment) -> impl Iterator<Item = (u16, &[u8])> {
+    segment
+        .data
+        .chunks(MAX_CHUNK)
+        .enumerate()
+        .map(move |(i, chunk)| (segment.address + (i * MAX_CHUNK) as u16, chunk))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::transport::{Exchange, MockTransport};
+
+    fn write(address: u16, data: &[u8]) -> Exchange {
+        Exchange::ControlOut {
+            request_type: 0x40,
+            request: FIRMWARE_LOAD,
+            value: address,
+            index: 0,
+            data: data.to_vec(),
+        }
+    }
+
+    fn read(address: u16, data: &[u8]) -> Exchange {
+        Exchange::ControlIn {
+            request_type: 0xc0,
+            request: FIRMWARE_LOAD,
+            value: address,
+            index: 0,
+            response: data.to_vec(),
+        }
+    }
+
+    fn image() -> FirmwareImage {
+        FirmwareImage::from_segments(vec![
+            Segment {
+                address: 0x0000,
+                data: vec![0x02, 0x00, 0x80],
+            },
+            Segment {
+                address: 0x0080,
+                data: vec![0x12; MAX_CHUNK + 1],
+            },
+        ])
+    }
+
+    #[test]
+    fn load_writes_verifies_and_releases_reset() {
+        let transport = MockTransport::new([
+            write(CPUCS, &[1]),
+            write(0x0000, &[0x02, 0x00, 0x80]),
+            write(0x0080, &[0x12; MAX_CHUNK]),
+            write(0x0080 + MAX_CHUNK as u16, &[0x12]),
+            read(0x0000, &[0x02, 0x00, 0x80]),
+            read(0x0080, &[0x12; MAX_CHUNK]),
+            read(0x0080 + MAX_CHUNK as u16, &[0x12]),
+            write(CPUCS, &[0]),
+        ]);
+
+        load(&transport, &image()).unwrap();
+        transport.assert_done();
+    }
+
+    #[test]
+    fn readback_mismatch_keeps_cpu_in_reset() {
+        let transport = MockTransport::new([
+            write(CPUCS, &[1]),
+            write(0x0000, &[0x02, 0x00, 0x80]),
+            write(0x0080, &[0x12; MAX_CHUNK]),
+            write(0x0080 + MAX_CHUNK as u16, &[0x12]),
+            read(0x0000, &[0x02, 0xff, 0x80]),
+        ]);
+
+        let result = load(&transport, &image());
+
+        assert!(matches!(result, Err(Error::FirmwareVerify(0x0001))));
+        transport.assert_done();
+    }
+
+    #[test]
+    fn mismatch_past_64k_is_not_an_overflow() {
+        let image = FirmwareImage::from_segments(vec![Segment {
+            address: 0xffff,
+            data: vec![0x01, 0x02],
+        }]);
+        let transport = MockTransport::new([
+            write(CPUCS, &[1]),
+            write(0xffff, &[0x01, 0x02]),
+            read(0xffff, &[0x01, 0xff]),
+        ]);
+
+        let result = load(&transport, &image);
+
+        assert!(matches!(result, Err(Error::InvalidFirmware(_))));
+        transport.assert_done();
+    }
+}
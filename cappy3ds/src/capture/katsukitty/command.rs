@@ -0,0 +1,383 @@
+use std::fmt;
+
+// Everything the host sends to the Katsukity FX2 on endpoint 1 is a stream of one byte
+// opcodes, some followed by a count and that many fixed size items. The names below are
+// guesses from captured traffic, the comments say what we actually know.
+
+const WRITE: u8 = 0x60;
+const WRITE_REGISTERS: u8 = 0x61;
+const MARKER_64: u8 = 0x64;
+const CONFIGURE_PORT: u8 = 0x65;
+const MARKER_66: u8 = 0x66;
+const MARKER_70: u8 = 0x70;
+const VIDEO_MODE: u8 = 0x71;
+const EEPROM_READ: u8 = 0x38;
+const EEPROM_WRITE: u8 = 0x39;
+const FIFO_SETUP: [u8; 3] = [0x5b, 0x59, 0x03];
+const FIFO_START: u8 = 0x40;
+const FIFO_STOP: u8 = 0x41;
+
+// a 0x60 with this count carries raw bitstream bytes instead of words
+const BITSTREAM_COUNT: u8 = 0x1f;
+/// Largest bitstream slice sent in one `FpgaCommand::Bitstream`.
+pub const BITSTREAM_CHUNK: usize = BITSTREAM_COUNT as usize * 2;
+
+// trailing byte of every eeprom read the vendor tool sends
+const EEPROM_READ_SUFFIX: u8 = 0x30;
+
+/// One operation understood by the Katsukity FX2 firmware.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FpgaCommand {
+    /// 0x60: 16 bit words for the FPGA configuration port, sent big endian
+    Write(Vec<u16>),
+    /// 0x60 0x1f: a slice of the bitstream, always sent with a count of 31 words even when short
+    Bitstream(Vec<u8>),
+    /// 0x61: 16 bit words for the capture registers, these change with the pixel format
+    WriteRegisters(Vec<u16>),
+    /// 0x71: five byte records that also change with the pixel format
+    VideoMode(Vec<[u8; 5]>),
+    /// 0x64: no arguments, sent around configuration blocks
+    Marker64,
+    /// 0x66: no arguments, sent once before the bitstream
+    Marker66,
+    /// 0x70: no arguments, sent once before the bitstream
+    Marker70,
+    /// 0x65: routes the FPGA output to the data endpoint
+    ConfigurePort,
+    /// 0x5b 0x59 0x03: sent right before starting the FIFO
+    FifoSetup,
+    /// 0x40: start streaming frames on endpoint 0x82
+    FifoStart,
+    /// 0x41: stop streaming frames
+    FifoStop,
+    /// 0x38: read `len` EEPROM bytes from `offset`, the answer arrives on endpoint 0x81
+    EepromRead { offset: u8, len: u8 },
+    /// 0x39: write `data` to the EEPROM at `offset`
+    EepromWrite { offset: u8, data: Vec<u8> },
+    /// Bytes the decoder could not make sense of
+    Raw(Vec<u8>),
+}
+
+impl FpgaCommand {
+    /// Splits a byte sequence sent to endpoint 1 into commands.
+    ///
+    /// Never fails, anything that does not parse ends up in `FpgaCommand::Raw`.
+    pub fn decode(mut bytes: &[u8]) -> Vec<FpgaCommand> {
+        let mut commands = Vec::new();
+        let mut raw = Vec::new();
+
+        while !bytes.is_empty() {
+            match decode_one(bytes) {
+                Some((command, len)) => {
+                    if !raw.is_empty() {
+                        commands.push(FpgaCommand::Raw(std::mem::take(&mut raw)));
+                    }
+                    commands.push(command);
+                    bytes = &bytes[len..];
+                }
+                None => {
+                    raw.push(bytes[0]);
+                    bytes = &bytes[1..];
+                }
+            }
+        }
+
+        if !raw.is_empty() {
+            commands.push(FpgaCommand::Raw(raw));
+        }
+
+        commands
+    }
+
+    /// Renders a byte sequence as one named operation per line.
+    pub fn describe(bytes: &[u8]) -> String {
+        Self::decode(bytes)
+            .iter()
+            .map(|command| command.to_string())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Appends the wire encoding of this command to `out`.
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            FpgaCommand::Write(words) => encode_words(out, WRITE, words),
+            FpgaCommand::Bitstream(data) => {
+                out.extend_from_slice(&[WRITE, BITSTREAM_COUNT]);
+                out.extend_from_slice(data);
+            }
+            FpgaCommand::WriteRegisters(words) => encode_words(out, WRITE_REGISTERS, words),
+            FpgaCommand::VideoMode(records) => {
+                out.extend_from_slice(&[VIDEO_MODE, records.len() as u8]);
+                for record in records {
+                    out.extend_from_slice(record);
+                }
+            }
+            FpgaCommand::Marker64 => out.push(MARKER_64),
+            FpgaCommand::Marker66 => out.push(MARKER_66),
+            FpgaCommand::Marker70 => out.push(MARKER_70),
+            FpgaCommand::ConfigurePort => out.push(CONFIGURE_PORT),
+            FpgaCommand::FifoSetup => out.extend_from_slice(&FIFO_SETUP),
+            FpgaCommand::FifoStart => out.push(FIFO_START),
+            FpgaCommand::FifoStop => out.push(FIFO_STOP),
+            FpgaCommand::EepromRead { offset, len } => {
+                out.extend_from_slice(&[EEPROM_READ, *offset, *len, EEPROM_READ_SUFFIX])
+            }
+            FpgaCommand::EepromWrite { offset, data } => {
+                out.extend_from_slice(&[EEPROM_WRITE, *offset, data.len() as u8]);
+                out.extend_from_slice(data);
+            }
+            FpgaCommand::Raw(data) => out.extend_from_slice(data),
+        }
+    }
+}
+
+fn encode_words(out: &mut Vec<u8>, opcode: u8, words: &[u16]) {
+    out.extend_from_slice(&[opcode, words.len() as u8]);
+    for word in words {
+        out.extend_from_slice(&word.to_be_bytes());
+    }
+}
+
+impl fmt::Display for FpgaCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FpgaCommand::Write(words) => write!(f, "write {}", Words(words)),
+            FpgaCommand::Bitstream(data) => write!(f, "bitstream ({} bytes)", data.len()),
+            FpgaCommand::WriteRegisters(words) => write!(f, "registers {}", Words(words)),
+            FpgaCommand::VideoMode(records) => {
+                write!(f, "video mode [")?;
+                for (i, record) in records.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{}", hex::encode(record))?;
+                }
+                write!(f, "]")
+            }
+            FpgaCommand::Marker64 => write!(f, "marker 0x64"),
+            FpgaCommand::Marker66 => write!(f, "marker 0x66"),
+            FpgaCommand::Marker70 => write!(f, "marker 0x70"),
+            FpgaCommand::ConfigurePort => write!(f, "configure port"),
+            FpgaCommand::FifoSetup => write!(f, "fifo setup"),
+            FpgaCommand::FifoStart => write!(f, "fifo start"),
+            FpgaCommand::FifoStop => write!(f, "fifo stop"),
+            FpgaCommand::EepromRead { offset, len } => {
+                write!(f, "eeprom read {:#04x} bytes at {:#04x}", len, offset)
+            }
+            FpgaCommand::EepromWrite { offset, data } => {
+                write!(f, "eeprom write {} at {:#04x}", hex::encode(data), offset)
+            }
+            FpgaCommand::Raw(data) => write!(f, "raw {}", hex::encode(data)),
+        }
+    }
+}
+
+struct Words<'a>(&'a [u16]);
+
+impl fmt::Display for Words<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[")?;
+        for (i, word) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{:04x}", word)?;
+        }
+        write!(f, "]")
+    }
+}
+
+/// Builds the payload of one bulk write out of commands.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Packet {
+    bytes: Vec<u8>,
+}
+
+impl Packet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(mut self, command: FpgaCommand) -> Self {
+        command.encode(&mut self.bytes);
+        self
+    }
+
+    pub fn write(self, words: &[u16]) -> Self {
+        self.push(FpgaCommand::Write(words.to_vec()))
+    }
+
+    pub fn bitstream(self, data: &[u8]) -> Self {
+        self.push(FpgaCommand::Bitstream(data.to_vec()))
+    }
+
+    pub fn registers(self, words: &[u16]) -> Self {
+        self.push(FpgaCommand::WriteRegisters(words.to_vec()))
+    }
+
+    pub fn video_mode(self, records: &[[u8; 5]]) -> Self {
+        self.push(FpgaCommand::VideoMode(records.to_vec()))
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+// returns the command at the start of `bytes` and how many bytes it used
+fn decode_one(bytes: &[u8]) -> Option<(FpgaCommand, usize)> {
+    let command = match bytes[0] {
+        WRITE if bytes.get(1) == Some(&BITSTREAM_COUNT) => {
+            let len = (bytes.len() - 2).min(BITSTREAM_CHUNK);
+            return Some((FpgaCommand::Bitstream(bytes[2..2 + len].to_vec()), 2 + len));
+        }
+        WRITE => {
+            let (words, len) = decode_counted(bytes, 2)?;
+            return Some((FpgaCommand::Write(to_words(&words)), len));
+        }
+        WRITE_REGISTERS => {
+            let (words, len) = decode_counted(bytes, 2)?;
+            return Some((FpgaCommand::WriteRegisters(to_words(&words)), len));
+        }
+        VIDEO_MODE => {
+            let (records, len) = decode_counted(bytes, 5)?;
+            let records = records
+                .chunks(5)
+                .map(|record| [record[0], record[1], record[2], record[3], record[4]])
+                .collect();
+            return Some((FpgaCommand::VideoMode(records), len));
+        }
+        EEPROM_READ => match *bytes.get(1..4)? {
+            [offset, len, EEPROM_READ_SUFFIX] => {
+                return Some((FpgaCommand::EepromRead { offset, len }, 4))
+            }
+            _ => return None,
+        },
+        EEPROM_WRITE => {
+            // the offset sits between the opcode and the count
+            let offset = *bytes.get(1)?;
+            let (data, len) = decode_counted(&bytes[1..], 1)?;
+            return Some((FpgaCommand::EepromWrite { offset, data }, len + 1));
+        }
+        _ if bytes.starts_with(&FIFO_SETUP) => return Some((FpgaCommand::FifoSetup, 3)),
+        MARKER_64 => FpgaCommand::Marker64,
+        MARKER_66 => FpgaCommand::Marker66,
+        MARKER_70 => FpgaCommand::Marker70,
+        CONFIGURE_PORT => FpgaCommand::ConfigurePort,
+        FIFO_START => FpgaCommand::FifoStart,
+        FIFO_STOP => FpgaCommand::FifoStop,
+        _ => return None,
+    };
+
+    Some((command, 1))
+}
+
+// reads `<opcode> <count> <count * size bytes>`, returning the items and total length
+fn decode_counted(bytes: &[u8], size: usize) -> Option<(Vec<u8>, usize)> {
+    let count = *bytes.get(1)? as usize;
+    let end = 2 + count * size;
+
+    Some((bytes.get(2..end)?.to_vec(), end))
+}
+
+fn to_words(bytes: &[u8]) -> Vec<u16> {
+    bytes
+        .chunks(2)
+        .map(|word| u16::from_be_bytes([word[0], word[1]]))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(commands: &[FpgaCommand]) -> Vec<u8> {
+        commands
+            .iter()
+            .cloned()
+            .fold(Packet::new(), Packet::push)
+            .into_bytes()
+    }
+
+    #[test]
+    fn commands_round_trip() {
+        let commands = vec![
+            FpgaCommand::Marker64,
+            FpgaCommand::Write(vec![0x30ff, 0x60c9]),
+            FpgaCommand::WriteRegisters(vec![0x000f, 0x003e]),
+            FpgaCommand::VideoMode(vec![[0x8f, 0x9d, 0xb7, 0x26, 0x68]]),
+            FpgaCommand::ConfigurePort,
+            FpgaCommand::Marker66,
+            FpgaCommand::Marker70,
+            FpgaCommand::FifoSetup,
+            FpgaCommand::FifoStart,
+            FpgaCommand::FifoStop,
+            FpgaCommand::EepromRead {
+                offset: 0x10,
+                len: 0x10,
+            },
+            FpgaCommand::EepromWrite {
+                offset: 0x20,
+                data: vec![1, 2, 3],
+            },
+            // a bitstream slice swallows whatever follows it, so it has to come last
+            FpgaCommand::Bitstream(vec![0xaa; BITSTREAM_CHUNK]),
+        ];
+
+        assert_eq!(FpgaCommand::decode(&encode(&commands)), commands);
+    }
+
+    #[test]
+    fn known_packets_encode_like_the_captures() {
+        assert_eq!(
+            hex::encode(encode(&[
+                FpgaCommand::Write(vec![0x30ff, 0x60c5]),
+                FpgaCommand::EepromRead {
+                    offset: 0x70,
+                    len: 0x10
+                },
+            ])),
+            "600230ff60c538701030"
+        );
+        assert_eq!(
+            hex::encode(Packet::new().bitstream(&[1, 2]).bytes()),
+            "601f0102"
+        );
+    }
+
+    #[test]
+    fn unknown_bytes_decode_as_raw() {
+        assert_eq!(
+            FpgaCommand::decode(&[0x01, 0x02, 0x40, 0xee]),
+            vec![
+                FpgaCommand::Raw(vec![0x01, 0x02]),
+                FpgaCommand::FifoStart,
+                FpgaCommand::Raw(vec![0xee]),
+            ]
+        );
+
+        // counts that run past the end, and an eeprom read with the wrong suffix
+        assert_eq!(
+            FpgaCommand::decode(&[0x61, 0x02, 0x00]),
+            vec![FpgaCommand::Raw(vec![0x61, 0x02, 0x00])]
+        );
+        assert_eq!(
+            FpgaCommand::decode(&[0x38, 0x00, 0x10, 0x31]),
+            vec![FpgaCommand::Raw(vec![0x38, 0x00, 0x10, 0x31])]
+        );
+        assert_eq!(FpgaCommand::decode(&[]), vec![]);
+    }
+
+    #[test]
+    fn describe_names_every_command() {
+        assert_eq!(
+            FpgaCommand::describe(&hex::decode("646001ffff600200ff00ff").unwrap()),
+            "marker 0x64\nwrite [ffff]\nwrite [00ff 00ff]"
+        );
+    }
+}
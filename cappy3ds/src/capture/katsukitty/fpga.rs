@@ -1,10 +1,16 @@
-use std::time::Duration;
-use std::{thread, time};
-extern crate libusb1_sys as usbffi;
+use std::thread;
+use std::time::{Duration, Instant};
 
-use rusb::{DeviceHandle, UsbContext};
+use super::command::{FpgaCommand, Packet, BITSTREAM_CHUNK};
+use crate::capture::devices::Endpoints;
+use crate::capture::transport::Transport;
+use crate::capture::{ColorDepth, POLL_INTERVAL};
+use crate::Error;
 
-pub fn read_eeprom<T: UsbContext>(handle: &mut DeviceHandle<T>) {
+pub fn read_eeprom<H: Transport + ?Sized>(
+    handle: &H,
+    endpoints: &Endpoints,
+) -> Result<Vec<u8>, Error> {
     let timeout = Duration::from_secs(1);
 
     let mut offset = 0;
@@ -14,31 +20,317 @@ pub fn read_eeprom<T: UsbContext>(handle: &mut DeviceHandle<T>) {
     let mut buf = [0; 16];
 
     while offset <= 0x70 {
-        match handle.write_bulk(1, &[0x38, offset, 0x10, 0x30], timeout) {
-            Ok(_) => {}
-            Err(err) => println!("could not write to endpoint: {}", err),
-        }
+        handle
+            .write_bulk(
+                endpoints.command,
+                Packet::new()
+                    .push(FpgaCommand::EepromRead { offset, len: 0x10 })
+                    .bytes(),
+                timeout,
+            )
+            .map_err(Error::TransferFailed)?;
 
-        match handle.read_bulk(0x81, &mut buf, timeout) {
-            Ok(len) => {
-                //println!("{:02X?}", buf);
-            }
-            Err(err) => println!("could not read from endpoint: {}", err),
-        }
+        handle
+            .read_bulk(endpoints.response, &mut buf, timeout)
+            .map_err(Error::TransferFailed)?;
 
         eeprom.extend_from_slice(&buf);
 
         offset += 0x10;
     }
 
-    //println!("{:02X?}", eeprom);
+    Ok(eeprom)
+}
+
+// 0x39 is the write counterpart of the 0x38 read. Not captured from the vendor tool, so
+// callers must check the card against a backup first and read back and compare after.
+pub fn write_eeprom<H: Transport + ?Sized>(
+    handle: &H,
+    endpoints: &Endpoints,
+    data: &[u8],
+) -> Result<(), Error> {
+    let timeout = Duration::from_secs(1);
+
+    for (offset, chunk) in (0..).step_by(0x10).zip(data.chunks(0x10)) {
+        let packet = Packet::new().push(FpgaCommand::EepromWrite {
+            offset,
+            data: chunk.to_vec(),
+        });
+
+        handle
+            .write_bulk(endpoints.command, packet.bytes(), timeout)
+            .map_err(Error::TransferFailed)?;
+
+        // let the EEPROM finish its write cycle before the next page
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    Ok(())
+}
+
+fn write_packet<H: Transport + ?Sized>(
+    handle: &H,
+    endpoints: &Endpoints,
+    packet: &Packet,
+    timeout: Duration,
+) -> Result<(), Error> {
+    handle
+        .write_bulk(endpoints.command, packet.bytes(), timeout)
+        .map_err(Error::FpgaConfig)?;
+
+    Ok(())
+}
+
+// Sent before the bitstream, as captured from the vendor tool.
+fn setup_packets() -> Vec<Packet> {
+    let select = Packet::new()
+        .write(&[0x30ff, 0x60c9])
+        .write(&[0x20ff])
+        .registers(&[0x00ff, 0x00ff, 0x00ff, 0x80ff])
+        .write(&[0x01ff]);
+
+    vec![
+        Packet::new()
+            .push(FpgaCommand::Marker64)
+            .write(&[0xffff])
+            .write(&[0x00ff, 0x00ff]),
+        select.clone(),
+        select,
+        Packet::new().push(FpgaCommand::Marker70),
+        Packet::new()
+            .write(&[0x30ff, 0x60d0])
+            .write(&[0x30ff, 0x60cb])
+            .write(&[0x00ff, 0x00ff]),
+        Packet::new()
+            .write(&[0x30ff, 0x60f1])
+            .write(&[0x20ff])
+            .registers(&[
+                0x00ff, 0x00ff, 0x00ff, 0x00ff, 0x00ff, 0x00ff, 0x00ff, 0x80ff,
+            ])
+            .write(&[0x01ff])
+            .write(&[0x00ff, 0x00ff]),
+        Packet::new()
+            .write(&[0x00ff, 0x00ff])
+            .write(&[0x30ff, 0x60cb])
+            .write(&[0x30ff, 0x60c5])
+            .push(FpgaCommand::Marker66)
+            .push(FpgaCommand::Marker64)
+            .write(&[0x30ff, 0x60c5]),
+        Packet::new().write(&[0x20ff]),
+        Packet::new().write(&[0x0000, 0x0000, 0x0000, 0x8000]),
+        Packet::new().write(&[0x01ff]),
+        Packet::new().write(&[0x30ff, 0x60c5]),
+        Packet::new().write(&[0x20ff]),
+    ]
+}
+
+// Sent after the bitstream, the 0x71 and 0x61 blocks pick the pixel format.
+fn mode_packets(color_depth: ColorDepth) -> Vec<Packet> {
+    let (video_mode, registers) = match color_depth {
+        ColorDepth::Rgb565 => (
+            [0x5e, 0x01, 0x40, 0xc3, 0x00],
+            [0x000f, 0x003e, 0x00f8, 0x0010, 0x0056, 0x800a, 0x0100],
+        ),
+        ColorDepth::Rgb888 => (
+            [0x5e, 0x01, 0x4f, 0x08, 0x00],
+            [0x008f, 0x003c, 0x00f2, 0x0038, 0x0056, 0x800a, 0x0100],
+        ),
+    };
+
+    vec![
+        Packet::new().write(&[
+            0x0000, 0x0004, 0x0000, 0x0004, 0x0000, 0x0004, 0x0000, 0x0004, 0x0000, 0x0004,
+            0x8000,
+        ]),
+        Packet::new().write(&[0x01ff]),
+        Packet::new()
+            .write(&[0x30ff, 0x60d6])
+            .write(&[0x00ff, 0x00ff])
+            .write(&[0x30ff, 0x60ff])
+            .write(&[0x20ff]),
+        Packet::new().write(&[0x8000]),
+        Packet::new().write(&[0x01ff]),
+        Packet::new()
+            .write(&[0x30ff, 0x60cc])
+            .write(&[0x00ff, 0x00ff])
+            .write(&[0x30ff, 0x60ff])
+            .write(&[0x30ff, 0x60ff]),
+        Packet::new()
+            .video_mode(&[
+                [0x8f, 0x9d, 0xb7, 0x26, 0x68],
+                video_mode,
+                [0x00, 0x02, 0x30, 0xff, 0x60],
+            ])
+            .push(FpgaCommand::ConfigurePort),
+        Packet::new()
+            .push(FpgaCommand::Marker64)
+            .write(&[0x00ff, 0x00ff])
+            .write(&[0x30ff, 0x60c2])
+            .write(&[0x20ff]),
+        Packet::new().registers(&registers),
+    ]
+}
+
+// "(C)tan" followed by 0xff, sent once the bitstream has been accepted
+pub(crate) const FPGA_BANNER: [u8; 7] = [0x28, 0x43, 0x29, 0x74, 0x61, 0x6e, 0xff];
+
+// what the FX2 answers on the response endpoint while the FPGA has no bitstream
+pub(crate) const FPGA_EMPTY_RESPONSE: [u8; 7] = [0x09, 0x02, 0x27, 0x00, 0x01, 0x01, 0x00];
+
+pub fn configure_fpga<H: Transport + ?Sized>(
+    handle: &H,
+    endpoints: &Endpoints,
+    bitstream: Vec<u8>,
+    color_depth: ColorDepth,
+    response_timeout: Duration,
+) -> Result<(), Error> {
+    let timeout = Duration::from_secs(1);
+
+    for packet in setup_packets() {
+        write_packet(handle, endpoints, &packet, timeout)?;
+    }
+
+    for chunk in bitstream.chunks(BITSTREAM_CHUNK) {
+        write_packet(handle, endpoints, &Packet::new().bitstream(chunk), timeout)?;
+    }
+
+    for packet in mode_packets(color_depth) {
+        write_packet(handle, endpoints, &packet, timeout)?;
+    }
+
+    // bulk read to get (C)tan
+    let mut buf = [0; 7];
+    let deadline = Instant::now() + response_timeout;
+    let len = loop {
+        match handle.read_bulk(endpoints.response, &mut buf, POLL_INTERVAL) {
+            Ok(len) if len > 0 => break len,
+            Ok(_) | Err(rusb::Error::Timeout) if Instant::now() < deadline => {}
+            Ok(_) | Err(rusb::Error::Timeout) => return Err(Error::FpgaTimeout),
+            Err(err) => return Err(Error::FpgaConfig(err)),
+        }
+    };
+    log::debug!("FPGA banner {:02X?}", &buf[..len]);
+
+    if buf[..len] != FPGA_BANNER {
+        return Err(Error::FpgaBanner(buf[..len].to_vec()));
+    }
+
+    Ok(())
+}
+
+/// Works out whether the FPGA already has a bitstream by listening on the response endpoint.
+///
+/// With an empty FPGA the FX2 answers with a fixed 7 byte pattern. Anything else, or silence for
+/// the whole window, means the FPGA was configured by an earlier session. A single read straight
+/// after claiming the interface raced the FX2 in release builds, so keep listening for `timeout`.
+pub fn check_fpga_programmed<H: Transport + ?Sized>(
+    handle: &H,
+    endpoints: &Endpoints,
+    timeout: Duration,
+) -> Result<bool, Error> {
+    let mut buf = [0; 7];
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        match handle.read_bulk(endpoints.response, &mut buf, POLL_INTERVAL) {
+            Ok(len) if len > 0 => break,
+            Ok(_) | Err(rusb::Error::Timeout) if Instant::now() < deadline => {}
+            Ok(_) | Err(rusb::Error::Timeout) => return Ok(true),
+            Err(err) => return Err(Error::TransferFailed(err)),
+        }
+    }
+
+    log::debug!("FPGA response {:02X?}", buf);
+
+    if buf == FPGA_EMPTY_RESPONSE {
+        log::debug!("FPGA is empty");
+        return Ok(false);
+    }
+
+    Ok(true)
+}
+
+pub fn configure_port<H: Transport + ?Sized>(
+    handle: &H,
+    endpoints: &Endpoints,
+) -> Result<(), Error> {
+    let timeout = Duration::from_secs(1);
+
+    handle
+        .write_bulk(
+            endpoints.command,
+            Packet::new().push(FpgaCommand::ConfigurePort).bytes(),
+            timeout,
+        )
+        .map_err(Error::FpgaConfig)?;
+
+    Ok(())
 }
 
-pub fn configure_fpga<T: UsbContext>(handle: &mut DeviceHandle<T>, bitstream: Vec<u8>) {
+pub fn fifo_start<H: Transport + ?Sized>(
+    handle: &H,
+    endpoints: &Endpoints,
+) -> Result<(), Error> {
     let timeout = Duration::from_secs(1);
 
-    // ???
-    let commands = [
+    handle
+        .write_bulk(
+            endpoints.command,
+            Packet::new().push(FpgaCommand::FifoSetup).bytes(),
+            timeout,
+        )
+        .map_err(Error::TransferFailed)?;
+    handle
+        .write_bulk(
+            endpoints.command,
+            Packet::new().push(FpgaCommand::FifoStart).bytes(),
+            timeout,
+        )
+        .map_err(Error::TransferFailed)?;
+
+    Ok(())
+}
+
+pub fn fifo_stop<H: Transport + ?Sized>(
+    handle: &H,
+    endpoints: &Endpoints,
+) -> Result<(), Error> {
+    let timeout = Duration::from_secs(1);
+
+    handle
+        .write_bulk(
+            endpoints.command,
+            Packet::new().push(FpgaCommand::FifoStop).bytes(),
+            timeout,
+        )
+        .map_err(Error::TransferFailed)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::transport::{Exchange, MockTransport};
+
+    const TIMEOUT: Duration = Duration::from_millis(50);
+
+    fn bulk_out(packet: &str) -> Exchange {
+        Exchange::BulkOut {
+            endpoint: 0x01,
+            data: hex::decode(packet).unwrap(),
+        }
+    }
+
+    fn response(data: &[u8]) -> Exchange {
+        Exchange::BulkIn {
+            endpoint: 0x81,
+            data: data.to_vec(),
+        }
+    }
+
+    // the packets as the original code sent them, written out from captured traffic
+    const SETUP_HEX: [&str; 12] = [
         "646001ffff600200ff00ff",
         "600230ff60c9600120ff610400ff00ff00ff80ff600101ff",
         "600230ff60c9600120ff610400ff00ff00ff80ff600101ff",
@@ -53,82 +345,135 @@ pub fn configure_fpga<T: UsbContext>(handle: &mut DeviceHandle<T>, bitstream: Ve
         "600120ff",
     ];
 
-    for command in commands {
-        let data = hex::decode(command).expect("Bad hex data");
-        match handle.write_bulk(1, &data, timeout) {
-            Ok(_) => {}
-            Err(err) => println!("could not program fpga: {}", err),
-        }
-    }
-
-    // bitstream?
-    let chunks = bitstream.chunks(62);
-    for chunk in chunks {
-        let mut buf = vec![0x60, 0x1f];
-        buf.extend(chunk);
-        handle.write_bulk(1, &buf, timeout);
-    }
-
-    // config??
-    let end_commands = [
+    const MODE_565_HEX: [&str; 9] = [
         "600b00000004000000040000000400000004000000048000",
         "600101ff",
         "600230ff60d6600200ff00ff600230ff60ff600120ff",
         "60018000",
         "600101ff",
         "600230ff60cc600200ff00ff600230ff60ff600230ff60ff",
-        "71038f9db726685e0140c300000230ff6065", // 565
-        // "71038f9db726685e014f0800000230ff6065", //888
+        "71038f9db726685e0140c300000230ff6065",
         "64600200ff00ff600230ff60c2600120ff",
-        "6107000f003e00f800100056800a0100", // 565
-                                            // "6107008f003c00f200380056800a0100" //888
+        "6107000f003e00f800100056800a0100",
+    ];
+
+    // the original code kept these two as comments next to their RGB565 versions
+    const MODE_888_HEX: [&str; 9] = [
+        MODE_565_HEX[0],
+        MODE_565_HEX[1],
+        MODE_565_HEX[2],
+        MODE_565_HEX[3],
+        MODE_565_HEX[4],
+        MODE_565_HEX[5],
+        "71038f9db726685e014f0800000230ff6065",
+        MODE_565_HEX[7],
+        "6107008f003c00f200380056800a0100",
     ];
 
-    for command in end_commands {
-        let data = hex::decode(command).expect("Bad hex data");
-        handle.write_bulk(1, &data, timeout);
+    fn mode_hex(color_depth: ColorDepth) -> [&'static str; 9] {
+        match color_depth {
+            ColorDepth::Rgb565 => MODE_565_HEX,
+            ColorDepth::Rgb888 => MODE_888_HEX,
+        }
     }
 
-    let sleep_time = time::Duration::from_millis(1000);
-    thread::sleep(sleep_time);
+    fn hex_packets(packets: Vec<Packet>) -> Vec<String> {
+        packets
+            .iter()
+            .map(|packet| hex::encode(packet.bytes()))
+            .collect()
+    }
 
-    // bulk read to get (C)tan
-    // 28432974616eff
-    let mut buf = [0; 7];
-    handle.read_bulk(0x81, &mut buf, timeout);
-    println!("{:02X?}", buf);
-}
+    // everything configure_fpga should send for `bitstream`
+    fn configuration(bitstream: &[u8], color_depth: ColorDepth) -> Vec<Exchange> {
+        let bitstream = bitstream
+            .chunks(62)
+            .map(|chunk| format!("601f{}", hex::encode(chunk)));
 
-pub fn check_fpga_programmed<T: UsbContext>(handle: &mut DeviceHandle<T>) -> bool {
-    let timeout = Duration::from_secs(1);
+        SETUP_HEX
+            .map(String::from)
+            .into_iter()
+            .chain(bitstream)
+            .chain(mode_hex(color_depth).map(String::from))
+            .map(|packet| bulk_out(&packet))
+            .collect()
+    }
 
-    let mut buf = [0; 7];
-    handle.read_bulk(0x81, &mut buf, timeout);
-    println!("FPGA Response {:02X?}", buf);
+    #[test]
+    fn packets_match_the_original_hex() {
+        assert_eq!(hex_packets(setup_packets()), SETUP_HEX);
+        assert_eq!(hex_packets(mode_packets(ColorDepth::Rgb565)), MODE_565_HEX);
+        assert_eq!(hex_packets(mode_packets(ColorDepth::Rgb888)), MODE_888_HEX);
+    }
 
-    if buf == [0x09, 0x02, 0x27, 0x00, 0x01, 0x01, 0x00] {
-        println!("FPGA Empty Response");
-        return false;
+    #[test]
+    fn empty_fpga_is_detected() {
+        let transport = MockTransport::new([response(&[0x09, 0x02, 0x27, 0x00, 0x01, 0x01, 0x00])]);
+
+        let programmed =
+            check_fpga_programmed(&transport, &Endpoints::default(), TIMEOUT).unwrap();
+
+        assert!(!programmed);
+        transport.assert_done();
     }
 
-    return true;
-}
+    #[test]
+    fn silent_fpga_is_programmed() {
+        let transport = MockTransport::default();
 
-pub fn configure_port<T: UsbContext>(handle: &mut DeviceHandle<T>) {
-    let timeout = Duration::from_secs(1);
+        assert!(check_fpga_programmed(&transport, &Endpoints::default(), TIMEOUT).unwrap());
+    }
 
-    handle.write_bulk(1, &[0x65], timeout);
-}
+    #[test]
+    fn configuration_waits_for_banner() {
+        let bitstream = vec![0xa5; BITSTREAM_CHUNK * 2 + 5];
 
-pub fn fifo_start<T: UsbContext>(handle: &mut DeviceHandle<T>) {
-    let timeout = Duration::from_secs(1);
+        let transport = MockTransport::new(configuration(&bitstream, ColorDepth::Rgb888));
+        transport.push([response(&FPGA_BANNER)]);
 
-    handle.write_bulk(1, &[0x5b, 0x59, 0x03], timeout);
-    handle.write_bulk(1, &[0x40], timeout);
-}
+        configure_fpga(
+            &transport,
+            &Endpoints::default(),
+            bitstream,
+            ColorDepth::Rgb888,
+            TIMEOUT,
+        )
+        .unwrap();
+        transport.assert_done();
+    }
 
-pub fn fifo_stop<T: UsbContext>(handle: &mut DeviceHandle<T>) {
-    let timeout = Duration::from_secs(1);
+    #[test]
+    fn configuration_rejects_other_answers() {
+        let bitstream = vec![0x5a; 10];
+
+        let transport = MockTransport::new(configuration(&bitstream, ColorDepth::Rgb565));
+        transport.push([response(b"nope")]);
+
+        let result = configure_fpga(
+            &transport,
+            &Endpoints::default(),
+            bitstream,
+            ColorDepth::Rgb565,
+            TIMEOUT,
+        );
 
-    handle.write_bulk(1, &[0x41], timeout);
+        assert!(matches!(result, Err(Error::FpgaBanner(answer)) if answer == b"nope"));
+    }
+
+    #[test]
+    fn configuration_times_out() {
+        let bitstream = vec![0x00; 4];
+
+        let transport = MockTransport::new(configuration(&bitstream, ColorDepth::Rgb565));
+
+        let result = configure_fpga(
+            &transport,
+            &Endpoints::default(),
+            bitstream,
+            ColorDepth::Rgb565,
+            TIMEOUT,
+        );
+
+        assert!(matches!(result, Err(Error::FpgaTimeout)));
+    }
 }
@@ -1,66 +1,51 @@
-use std::time::Duration;
-extern crate libusb1_sys as usbffi;
-
-use rusb::{DeviceHandle, Direction, Recipient, RequestType, UsbContext};
-
-pub(crate) fn send_firmware<T: UsbContext>(handle: &mut DeviceHandle<T>, firmware: Vec<u8>) {
-    let timeout = Duration::from_secs(1);
-
-    let request_type = rusb::request_type(Direction::Out, RequestType::Vendor, Recipient::Device);
-
-    handle.set_active_configuration(1);
-
-    handle.claim_interface(0);
-
-    // Reset Device
-    match handle.write_control(request_type, 0xa0, 0xe600, 0, &[0x01], timeout) {
-        Ok(_) => println!("Successfully reset the FX2 chip for programming"),
-        Err(_) => todo!(),
-    };
-
-    // Send Firmware bits (can be changed for config??)
-    match handle.write_control(request_type, 0xa0, 0x0, 0, &[0x02, 0x09, 0x92], timeout) {
-        Ok(_) => print!("."),
-        Err(_) => todo!(),
-    };
-
-    match handle.write_control(request_type, 0xa0, 0x000b, 0, &[0x02, 0x0d, 0x9b], timeout) {
-        Ok(_) => print!("."),
-        Err(_) => todo!(),
-    };
-
-    match handle.write_control(request_type, 0xa0, 0x0033, 0, &[0x02, 0x0d, 0xe9], timeout) {
-        Ok(_) => print!("."),
-        Err(_) => todo!(),
-    };
-
-    match handle.write_control(request_type, 0xa0, 0x0043, 0, &[0x02, 0x08, 0x00], timeout) {
-        Ok(_) => print!("."),
-        Err(_) => todo!(),
-    };
-
-    match handle.write_control(request_type, 0xa0, 0x0053, 0, &[0x02, 0x08, 0x00], timeout) {
-        Ok(_) => print!("."),
-        Err(_) => todo!(),
-    };
+use crate::capture::fx2::{self, FirmwareImage, Segment};
+use crate::capture::transport::Transport;
+use crate::Error;
+
+// LJMPs for the reset and interrupt vectors, firm.bin only holds the code from 0x0080 up
+const VECTORS: &[(u16, [u8; 3])] = &[
+    (0x0000, [0x02, 0x09, 0x92]),
+    (0x000b, [0x02, 0x0d, 0x9b]),
+    (0x0033, [0x02, 0x0d, 0xe9]),
+    (0x0043, [0x02, 0x08, 0x00]),
+    (0x0053, [0x02, 0x08, 0x00]),
+];
+
+const CODE_ADDRESS: u16 = 0x0080;
+
+// the bare FX2 only has the one configuration and interface
+const FX2_CONFIGURATION: u8 = 1;
+const FX2_INTERFACE: u8 = 0;
+
+/// The embedded `firm.bin` together with the vectors it expects.
+pub(crate) fn firmware_image(firmware: &[u8]) -> FirmwareImage {
+    let mut segments: Vec<Segment> = VECTORS
+        .iter()
+        .map(|(address, data)| Segment {
+            address: *address,
+            data: data.to_vec(),
+        })
+        .collect();
+
+    segments.push(Segment {
+        address: CODE_ADDRESS,
+        data: firmware.to_vec(),
+    });
+
+    FirmwareImage::from_segments(segments)
+}
 
-    // Send more Firmware
-    let mut rom_address = 0x0080;
-    let chunks = firmware.chunks(1023);
-    for chunk in chunks {
-        println!("Address {:x}", rom_address);
-        match handle.write_control(request_type, 0xa0, rom_address, 0, chunk, timeout) {
-            Ok(_) => print!("."),
-            Err(_) => todo!(),
-        };
-        rom_address += chunk.len() as u16;
-    }
+pub(crate) fn send_firmware<H: Transport + ?Sized>(
+    handle: &H,
+    image: &FirmwareImage,
+) -> Result<(), Error> {
+    handle
+        .set_active_configuration(FX2_CONFIGURATION)
+        .map_err(Error::from_open)?;
 
-    println!("\nFX2 Firmware Flashed");
+    handle
+        .claim_interface(FX2_INTERFACE)
+        .map_err(Error::from_open)?;
 
-    // Reset Again
-    match handle.write_control(request_type, 0xa0, 0xe600, 0, &[0x00], timeout) {
-        Ok(_) => println!("Successfully reset the FX2 chip for re-enumeration"),
-        Err(_) => todo!(),
-    };
+    fx2::load(handle, image)
 }
@@ -1,330 +1,515 @@
-use memchr::memmem;
-use rust_embed::RustEmbed;
-use std::ffi::c_void;
-use std::slice;
-use std::sync::atomic::{AtomicBool, Ordering};
+use bytes::BytesMut;
+use std::path::Path;
+use std::sync::mpsc::{Receiver, SyncSender, TryRecvError};
 use std::sync::{Arc, Mutex};
 use std::{thread, time};
-extern crate libusb1_sys as usbffi;
-use bytes::BytesMut;
-use simple_error::SimpleError;
-
-
-
-use rusb::{Device, DeviceDescriptor, DeviceHandle, UsbContext};
-
-use super::Capture;
 
+use super::bus::{OpenDevice, UsbBus, UsbHandle, UsbSelector};
+use super::devices::DeviceProfile;
+use super::transfer::TransferConfig;
+use super::transport::Transport;
+use super::worker::{self, CaptureDriver};
+use super::{Capture, CaptureBackend, ColorDepth, ConnectConfig, DataCallback, HandleSlot};
+use crate::capabilities;
+use crate::firmware::{self, KnownImage};
+use crate::recording::RecordingWriter;
+use crate::session::{CaptureEvent, CaptureSession, Command};
+use crate::{Capabilities, DeviceInfo, EepromDump, Error};
+use parse::LineParser;
+
+pub mod command;
 mod fpga;
 mod fx2;
-mod image;
-mod parse;
+pub mod parse;
+pub mod replay;
+#[cfg(any(test, feature = "test-support"))]
+pub mod sim;
 
-#[derive(RustEmbed)]
+#[cfg(feature = "embedded-firmware")]
+#[derive(rust_embed::RustEmbed)]
 #[folder = "resources/Katsukity/"]
 struct KatsukityResources;
 
-pub struct Katsukity {
+// folder holding our files in a firmware directory
+const RESOURCE_DIR: &str = "Katsukity";
 
+#[cfg(feature = "embedded-firmware")]
+fn embedded_resource(name: &str) -> Option<Vec<u8>> {
+    KatsukityResources::get(name).map(|file| file.data.to_vec())
 }
 
-impl Katsukity {
-    pub fn new() -> Self {
-        return Self{
+#[cfg(not(feature = "embedded-firmware"))]
+fn embedded_resource(_name: &str) -> Option<Vec<u8>> {
+    None
+}
 
-        };
+// files shipped with the vendor software that we have seen work
+const KNOWN_IMAGES: &[KnownImage] = &[
+    KnownImage {
+        name: "firm.bin",
+        variant: "New 3DS XL",
+        sha256: "7e56f75daaf0202fa5f6d7824d8f2ecc87956f81cb47cb83bd4e5140f323ef36",
+    },
+    KnownImage {
+        name: "bitstream.bin",
+        variant: "New 3DS XL",
+        sha256: "f56ce486faa7326b5f9da56e1e9991d9f7c6f82fef0cfc2ee9eb41b1ba830b2e",
+    },
+];
+
+fn load_resource(config: &ConnectConfig, name: &str) -> Result<Vec<u8>, Error> {
+    let data = config
+        .firmware_source
+        .load(RESOURCE_DIR, name, embedded_resource)?;
+
+    match firmware::verify_image(name, &data, KNOWN_IMAGES) {
+        Ok(variant) => log::info!("{} matches {}", name, variant),
+        Err(err) if config.allow_unknown_firmware => log::warn!("{}, uploading anyway", err),
+        Err(err) => return Err(err),
     }
+
+    Ok(data)
 }
 
-impl Capture for Katsukity {
-    fn connect<T: UsbContext>(context: &mut T) -> Result<DeviceHandle<T>, SimpleError> {
-        let firmware = KatsukityResources::get("firm.bin").unwrap();
-        let bitstream = KatsukityResources::get("bitstream.bin").unwrap();
-    
-        let vid = 0x0752;
-        let pid = 0x8613;
-    
-        let mut flashed_fx2 = false;
-        match Self::open_device(context, vid, pid) {
-            Some((mut device, device_desc, mut handle)) => {
-                println!("Opened {:04x}:{:04x}", vid, pid);
-                fx2::send_firmware(&mut handle, firmware.data.to_vec());
-                flashed_fx2 = true;
-            }
-            None => {
-                println!("could not find FX2 device");
-            }
-        }
-    
-        if flashed_fx2 {
-            println!("Waiting for second interface");
-            let sleep_time = time::Duration::from_millis(5000);
-            thread::sleep(sleep_time);
-            // todo: loop with device check instead of sleeping
-        }
-    
-        match Self::open_device(context, 0x0752, 0xf2c0) {
-            Some((mut device, device_desc, mut handle)) => {
-                println!("Opened secondary device");
-                match handle.claim_interface(0) {
-                    Ok(_) => {}
-                    Err(err) => panic!("could not claim second device: {}", err),
-                }
-    
-                // bleh apparently relesase runs fast enough to break this
-                // add in some sleeps
-                //if fpga::check_fpga_programmed(&mut handle) {
-                //} else {
-                fpga::read_eeprom(&mut handle);
-                fpga::configure_fpga(&mut handle, bitstream.data.to_vec());
-                fpga::configure_port(&mut handle);
-                //}
-    
-                fpga::fifo_start(&mut handle);
-    
-                Ok(handle)
-            }
-            None => Err(SimpleError::new(
-                "secondary device missing, firmware upload failed?",
-            )),
-        }
+// what a Katsukity delivers, whether live or replayed
+fn card_capabilities(color_depths: &'static [ColorDepth]) -> Capabilities {
+    Capabilities {
+        upper_screen: capabilities::UPPER_3DS,
+        lower_screen: capabilities::LOWER_3DS,
+        color_depths,
+        // the audio slice is the raw 16 byte header of every line, where the sound sits in
+        // them is not worked out yet, so none is advertised
+        audio: None,
+        stereoscopic: false,
+        frame_rate: capabilities::FRAME_RATE_3DS,
     }
 }
 
-
-pub fn do_capture<T: UsbContext, F>(handle: &mut DeviceHandle<T>, data_callback: F)
-where
-    F: FnMut(&[i16], BytesMut, BytesMut),
-{
-    bulk_read(handle, data_callback);
+/// How far along a Katsukity card is in its setup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CardState {
+    /// Bare FX2 (0752:8613) waiting for firmware
+    Fx2Unprogrammed,
+    /// FX2 firmware running (0752:f2c0) but the FPGA has no bitstream
+    FpgaUnconfigured,
+    /// FPGA configured, ready to capture
+    Configured,
 }
 
-extern "system" fn transfer_finished<T: UsbContext, F>(transfer_ptr: *mut usbffi::libusb_transfer)
-where
-    F: FnMut(&[i16], BytesMut, BytesMut),
-{
-    let transfer: &mut usbffi::libusb_transfer = unsafe { &mut *transfer_ptr };
+pub struct Katsukity {
+    device_handle: HandleSlot,
+    bus: Option<Arc<dyn UsbBus>>,
+    connect_config: ConnectConfig,
+    profile: DeviceProfile,
+}
 
-    let user_data = transfer.user_data;
+impl Katsukity {
+    pub fn new(profile: DeviceProfile) -> Self {
+        Self {
+            device_handle: Arc::new(Mutex::new(None)),
+            bus: None,
+            connect_config: ConnectConfig::default(),
+            profile,
+        }
+    }
+}
 
-    if user_data.is_null() {
-        // todo: some sort of cleanup????
-        return;
+impl CaptureBackend for Katsukity {
+    fn name(&self) -> &'static str {
+        "Katsukity"
     }
 
-    let buf = transfer.buffer;
+    fn profile(&self) -> &DeviceProfile {
+        &self.profile
+    }
 
-    let s = unsafe { slice::from_raw_parts(buf, TRANSFER_SIZE) };
+    fn capabilities(&self) -> Capabilities {
+        card_capabilities(&[ColorDepth::Rgb565, ColorDepth::Rgb888])
+    }
 
-    let start = memmem::find(s, &[0x33, 0xCC, 0x00, 0x00]);
+    fn connect(
+        &mut self,
+        bus: &Arc<dyn UsbBus>,
+        config: &ConnectConfig,
+    ) -> Result<DeviceInfo, Error> {
+        let (handle, info) = <Self as Capture>::connect(bus.as_ref(), config, &self.profile)?;
 
-    let handler = user_data as *mut Arc<Mutex<CaptureHandler<F>>>;
+        *self.device_handle.lock().unwrap() = Some(handle);
+        self.bus = Some(bus.clone());
+        self.connect_config = config.clone();
 
-    let mut handler = unsafe { (*handler).lock().unwrap() };
+        Ok(info)
+    }
 
-    let current_buffer = handler.current_buffer;
-    let frame_buffer_len = handler.buffers[current_buffer].len();
+    fn start(
+        &self,
+        data_callback: DataCallback,
+        transfer_config: TransferConfig,
+        auto_recover: bool,
+        record_to: Option<&Path>,
+    ) -> Result<CaptureSession, Error> {
+        let bus = self.bus.clone().ok_or(Error::NotConnected)?;
+
+        let color_depth = self.connect_config.color_depth_for(&self.profile);
+        let recorder = open_recorder(record_to, color_depth, &self.profile)?;
+
+        start_capture(
+            self.device_handle.clone(),
+            bus,
+            move || CaptureHandler::new(data_callback.clone(), color_depth, recorder.clone()),
+            transfer_config,
+            self.connect_config.clone(),
+            self.profile.clone(),
+            auto_recover,
+        )
+    }
 
-    let mut local_offset = 0;
-    let mut local_end = TRANSFER_SIZE;
-    let mut wait = false;
+    fn stop(&self) -> Result<(), Error> {
+        let handle = self.device_handle.lock().unwrap();
+        fpga::fifo_stop(
+            handle.as_deref().ok_or(Error::NotConnected)?,
+            &self.profile.endpoints,
+        )
+    }
 
-    if let Some(start) = start {
-        if frame_buffer_len == 0 {
-            local_offset = start;
-        } else {
-            local_end = start;
-        }
-    } else if frame_buffer_len == 0 {
-        // Wait for us to get the frame start code
-        wait = true;
+    fn read_eeprom(&self) -> Result<EepromDump, Error> {
+        let handle = self.device_handle.lock().unwrap();
+        read_eeprom(handle.as_deref().ok_or(Error::NotConnected)?, &self.profile)
     }
 
-    // if we don't need to wait for a new frame to start
-    if !wait {
-        // Move the data into the frame buffer
-        handler.buffers[current_buffer].extend(&s[local_offset..local_end]);
-
-        if let Some(start) = start {
-            if frame_buffer_len > 0 {
-                // parse since we have a frame
-                let (upper_buffer, lower_buffer, sound_buffer) =
-                    parse::split_capture_buffer(&handler.buffers[current_buffer]);
-
-                let (_, short, _) = unsafe { sound_buffer.align_to::<i16>() };
-                (handler.data_callback)(
-                    short,
-                    parse::rgb565_to_rgba(&upper_buffer),
-                    parse::rgb565_to_rgba(&lower_buffer),
-                );
-
-                handler.current_buffer += 1;
-                if handler.current_buffer >= NUM_BUFFERS {
-                    handler.current_buffer = 0;
-                }
+    fn restore_eeprom(&self, dump: &EepromDump, backup: &EepromDump) -> Result<(), Error> {
+        let handle = self.device_handle.lock().unwrap();
+        restore_eeprom(
+            handle.as_deref().ok_or(Error::NotConnected)?,
+            &self.profile,
+            dump,
+            backup,
+        )
+    }
+}
 
-                let current_buffer = handler.current_buffer;
+impl Capture for Katsukity {
+    fn connect(
+        bus: &dyn UsbBus,
+        config: &ConnectConfig,
+        profile: &DeviceProfile,
+    ) -> Result<(UsbHandle, DeviceInfo), Error> {
+        let endpoints = &profile.endpoints;
 
-                handler.buffers[current_buffer].clear();
-                handler.buffers[current_buffer].extend(&s[start..]);
+        let mut flashed_fx2 = false;
+        if let Some(fx2_id) = profile.quirks.fx2_upload {
+            match bus.open(UsbSelector::id(fx2_id.vendor_id, fx2_id.product_id))? {
+                Some(fx2_device) => {
+                    log::info!("Opened {:04x}:{:04x}", fx2_id.vendor_id, fx2_id.product_id);
+                    log::debug!("Card state {:?}", CardState::Fx2Unprogrammed);
+                    let image = match &config.fx2_firmware {
+                        Some(image) => image.clone(),
+                        None => fx2::firmware_image(&load_resource(config, "firm.bin")?),
+                    };
+                    fx2::send_firmware(&*fx2_device.handle, &image)?;
+                    flashed_fx2 = true;
+                }
+                None => {
+                    log::info!("could not find FX2 device");
+                }
             }
         }
-    }
 
-    if !handler.should_stop.load(Ordering::Relaxed) {
-        unsafe {
-            usbffi::libusb_submit_transfer(transfer_ptr);
+        let secondary = if flashed_fx2 {
+            log::info!("Waiting for second interface");
+            Some(Self::wait_for_device(
+                bus,
+                profile.vendor_id,
+                profile.product_id,
+                config.reenumeration_timeout,
+            )?)
+        } else {
+            bus.open(UsbSelector::id(profile.vendor_id, profile.product_id))?
+        };
+
+        match secondary {
+            Some(OpenDevice {
+                handle,
+                description,
+            }) => {
+                log::info!("Opened {}", profile.name);
+                for interface in &profile.interfaces {
+                    handle
+                        .claim_interface(*interface)
+                        .map_err(Error::from_open)?;
+                }
+
+                // a freshly flashed FX2 always comes up with an empty FPGA, only ask when
+                // we are picking up a card that was set up by an earlier run
+                let state = if flashed_fx2
+                    || !fpga::check_fpga_programmed(&*handle, endpoints, config.detect_timeout)?
+                {
+                    CardState::FpgaUnconfigured
+                } else {
+                    CardState::Configured
+                };
+                log::debug!("Card state {:?}", state);
+
+                let eeprom = fpga::read_eeprom(&*handle, endpoints)?;
+                let info = DeviceInfo::new(&description, "Katsukity", eeprom);
+                log::info!("Connected to {}", info);
+
+                // the format can't be read back, so only trust a configured card in the default one
+                let color_depth = config.color_depth_for(profile);
+                if state != CardState::Configured || color_depth != ColorDepth::default() {
+                    let bitstream = load_resource(config, "bitstream.bin")?;
+                    fpga::configure_fpga(
+                        &*handle,
+                        endpoints,
+                        bitstream,
+                        color_depth,
+                        config.fpga_timeout,
+                    )?;
+                    fpga::configure_port(&*handle, endpoints)?;
+                    thread::sleep(profile.quirks.post_config_delay());
+                }
+
+                Ok((handle, info))
+            }
+            None => Err(Error::DeviceNotFound),
         }
     }
 }
 
-#[derive(Debug)]
-struct CaptureHandler<F> {
-    buffers: Vec<BytesMut>,
-    current_buffer: usize,
-    new_frame: bool,
-    should_stop: AtomicBool,
-    data_callback: F,
+pub fn read_eeprom<H: Transport + ?Sized>(
+    handle: &H,
+    profile: &DeviceProfile,
+) -> Result<EepromDump, Error> {
+    EepromDump::new(fpga::read_eeprom(handle, &profile.endpoints)?)
 }
 
-const NUM_BUFFERS: usize = 20;
-
-// Top + Bottom width
-// 400 + 320 = 720
-// height + audio data
-// 240 + 8
-// RGB565
-// 2 bytes per pixel
-const FRAM_BUFFER_SIZE: usize = 720 * 248 * 2;
+/// Writes `dump` to the card, as long as `backup` shows its current contents were saved.
+///
+/// The card is read first and nothing is written unless it matches `backup`. Afterwards it is
+/// read again to make sure the write took.
+pub fn restore_eeprom<H: Transport + ?Sized>(
+    handle: &H,
+    profile: &DeviceProfile,
+    dump: &EepromDump,
+    backup: &EepromDump,
+) -> Result<(), Error> {
+    let current = read_eeprom(handle, profile)?;
+    if current != *backup {
+        return Err(Error::EepromBackupMismatch);
+    }
+    if current == *dump {
+        return Ok(());
+    }
 
-const TRANSFER_SIZE: usize = 0x4000;
+    fpga::write_eeprom(handle, &profile.endpoints, dump.data())?;
 
-impl<F> CaptureHandler<F>
-where
-    F: FnMut(&[i16], BytesMut, BytesMut),
-{
-    fn new(data_callback: F) -> Self {
-        let mut buffers = Vec::<BytesMut>::with_capacity(NUM_BUFFERS);
+    if read_eeprom(handle, profile)? != *dump {
+        return Err(Error::EepromVerifyFailed);
+    }
 
-        for i in 0..NUM_BUFFERS {
-            buffers.push(BytesMut::with_capacity(FRAM_BUFFER_SIZE));
-        }
+    Ok(())
+}
 
-        Self {
-            new_frame: false,
-            current_buffer: 0,
-            buffers,
-            should_stop: AtomicBool::new(false),
-            data_callback,
-        }
-    }
+/// Shared by every `CaptureHandler` of a session so a recording survives reconnects.
+type Recorder = Arc<Mutex<RecordingWriter>>;
+
+fn open_recorder(
+    record_to: Option<&Path>,
+    color_depth: ColorDepth,
+    profile: &DeviceProfile,
+) -> Result<Option<Recorder>, Error> {
+    record_to
+        .map(|path| RecordingWriter::create(path, color_depth, profile.endpoints.data))
+        .transpose()
+        .map(|recorder| recorder.map(|recorder| Arc::new(Mutex::new(recorder))))
 }
 
-fn bulk_read<T: UsbContext, F>(handle: &mut DeviceHandle<T>, data_callback: F)
+/// Streams frames into the handlers made by `new_handler`, a fresh one for every (re)connect.
+fn start_capture<F, N>(
+    device_handle: HandleSlot,
+    bus: Arc<dyn UsbBus>,
+    new_handler: N,
+    config: TransferConfig,
+    connect_config: ConnectConfig,
+    profile: DeviceProfile,
+    auto_recover: bool,
+) -> Result<CaptureSession, Error>
 where
-    F: FnMut(&[i16], BytesMut, BytesMut),
+    F: ?Sized + FnMut(&[i16], BytesMut, BytesMut) + Send + 'static,
+    N: Fn() -> CaptureHandler<F> + Send + 'static,
 {
-    println!("Starting Bulk Read");
-
-    let capture_handler = Arc::new(Mutex::new(CaptureHandler::new(data_callback)));
-
-    let should_stop = Arc::new(AtomicBool::new(false));
-    let stop_internal = Arc::clone(&should_stop);
-
-    let timeout = libc::timeval {
-        tv_sec: 1,
-        tv_usec: 0,
+    let streamer = Streamer {
+        new_handler,
+        config,
     };
 
-    thread::scope(|s| {
-        let thread_join_handle = s.spawn(|| loop {
-            if stop_internal.load(Ordering::Relaxed) {
-                println!("GOT STOP");
-                break;
-            }
-            unsafe {
-                usbffi::libusb_handle_events_timeout(
-                    handle.context().as_raw(),
-                    &timeout as *const libc::timeval,
-                );
-            }
-        });
-
-        //let mut transfers = vec![];
+    worker::spawn_capture(
+        device_handle,
+        bus,
+        connect_config,
+        profile,
+        auto_recover,
+        streamer,
+    )
+}
 
-        for _n in 0..10 {
-            let mut in_buf = [0u8; TRANSFER_SIZE];
-            let capture_handler = Box::new(capture_handler.clone());
+/// Runs the FIFO of a Katsukity while a session is capturing.
+struct Streamer<N> {
+    new_handler: N,
+    config: TransferConfig,
+}
 
-            let raw_transfer = Box::into_raw(capture_handler) as *mut c_void;
+impl<F, N> CaptureDriver for Streamer<N>
+where
+    F: ?Sized + FnMut(&[i16], BytesMut, BytesMut) + Send + 'static,
+    N: Fn() -> CaptureHandler<F> + Send + 'static,
+{
+    type Card = Katsukity;
 
-            //transfers.push(transfer);
+    fn start(&self, handle: &dyn Transport, profile: &DeviceProfile) -> Result<(), Error> {
+        fpga::fifo_start(handle, &profile.endpoints)
+    }
 
-            let lib_usb_transfer = unsafe { usbffi::libusb_alloc_transfer(0) };
+    fn read_frames(
+        &mut self,
+        handle: &dyn Transport,
+        profile: &DeviceProfile,
+        commands: &Receiver<Command>,
+        events: &SyncSender<CaptureEvent>,
+        paused: &mut bool,
+    ) -> Result<(), Error> {
+        bulk_read(
+            handle,
+            profile,
+            (self.new_handler)(),
+            self.config,
+            commands,
+            events.clone(),
+            paused,
+        )
+    }
 
-            // let user_data = Arc::into_raw(transfer.clone()) as *mut c_void;
+    fn stop(&self, handle: &dyn Transport, profile: &DeviceProfile) -> Result<(), Error> {
+        fpga::fifo_stop(handle, &profile.endpoints)
+    }
 
-            unsafe {
-                usbffi::libusb_fill_bulk_transfer(
-                    lib_usb_transfer,
-                    handle.as_raw(),
-                    0x82,
-                    in_buf.as_mut_ptr(),
-                    TRANSFER_SIZE.try_into().unwrap(),
-                    transfer_finished::<T, F> as _,
-                    raw_transfer,
-                    1000,
-                );
+    // an unplugged card shows up as I/O errors about as often as a missing device
+    fn is_device_lost(&self, err: &Error) -> bool {
+        matches!(
+            err,
+            Error::Disconnected
+                | Error::TransferFailed(rusb::Error::NoDevice)
+                | Error::TransferFailed(rusb::Error::Io)
+        )
+    }
+}
 
-                usbffi::libusb_submit_transfer(lib_usb_transfer);
-            }
-        }
+struct CaptureHandler<F: ?Sized> {
+    lines: LineParser,
+    data_callback: Arc<Mutex<F>>,
+    color_depth: ColorDepth,
+    recorder: Option<Recorder>,
+    events: Option<SyncSender<CaptureEvent>>,
+}
 
-        loop {
-            //let current_buffer = capture_handler.lock().unwrap().current_buffer;
-            //if current_buffer >= NUM_BUFFERS - 10 {
-            //break;
-            //}
-            //let ten_millis = time::Duration::from_millis(1);
-            //thread::sleep(ten_millis);
+impl<F> CaptureHandler<F>
+where
+    F: ?Sized + FnMut(&[i16], BytesMut, BytesMut) + Send + 'static,
+{
+    fn new(
+        data_callback: Arc<Mutex<F>>,
+        color_depth: ColorDepth,
+        recorder: Option<Recorder>,
+    ) -> Self {
+        Self {
+            lines: LineParser::new(color_depth),
+            data_callback,
+            color_depth,
+            recorder,
+            events: None,
         }
+    }
 
-        println!("Stopping Capture");
-
-        capture_handler
-            .lock()
-            .unwrap()
-            .should_stop
-            .store(true, Ordering::Relaxed);
-
-        let ten_millis = time::Duration::from_millis(10);
-        thread::sleep(ten_millis);
-
-        should_stop.store(true, Ordering::Relaxed);
-
-        println!("Waiting on Stop");
-
-        thread::sleep(ten_millis);
+    /// Reports damaged frames on `events`.
+    fn with_events(mut self, events: SyncSender<CaptureEvent>) -> Self {
+        self.events = Some(events);
+        self
+    }
 
-        match thread_join_handle.join() {
-            Ok(_) => println!("Thread OK"),
-            Err(e) => println!("Thread Err {:?}", e),
+    /// Appends the contents of one transfer, calling back whenever a full frame has arrived.
+    fn push(&mut self, s: &[u8]) {
+        let recorded = match &self.recorder {
+            Some(recorder) => recorder.lock().unwrap().record(s),
+            None => Ok(()),
+        };
+        if let Err(err) = recorded {
+            // keep capturing, a full disk shouldn't take the picture down with it
+            log::warn!("stopped recording: {}", err);
+            self.recorder = None;
         }
 
-        println!("PARSING IMAGE");
+        let color_depth = self.color_depth;
+        let data_callback = &self.data_callback;
+        let events = &self.events;
 
-        let handler = &capture_handler.lock().unwrap();
+        self.lines.push(s, |frame| {
+            if let (false, Some(events)) = (frame.damage.is_clean(), events) {
+                let _ = events.try_send(CaptureEvent::DamagedFrame(frame.damage));
+            }
 
-        let mut image = Vec::<u8>::new();
+            let (_, short, _) = unsafe { frame.audio.align_to::<i16>() };
+            (data_callback.lock().unwrap())(
+                short,
+                parse::to_rgba(&frame.upper, color_depth),
+                parse::to_rgba(&frame.lower, color_depth),
+            );
+        });
+    }
+}
 
-        for buffer in &handler.buffers {
-            image.extend_from_slice(&buffer);
+fn bulk_read<H: Transport + ?Sized, F>(
+    handle: &H,
+    profile: &DeviceProfile,
+    capture_handler: CaptureHandler<F>,
+    config: TransferConfig,
+    commands: &Receiver<Command>,
+    events: SyncSender<CaptureEvent>,
+    paused: &mut bool,
+) -> Result<(), Error>
+where
+    F: ?Sized + FnMut(&[i16], BytesMut, BytesMut) + Send + 'static,
+{
+    log::debug!("Starting bulk read");
+
+    let mut capture_handler = capture_handler.with_events(events.clone());
+    let mut stream = handle.stream(
+        profile.endpoints.data,
+        config,
+        Box::new(move |data: &[u8]| capture_handler.push(data)),
+        events,
+    )?;
+
+    // short enough that stop/pause requests are picked up promptly
+    let timeout = time::Duration::from_millis(100);
+
+    loop {
+        match commands.try_recv() {
+            Ok(Command::Pause) => {
+                fpga::fifo_stop(handle, &profile.endpoints)?;
+                *paused = true;
+            }
+            Ok(Command::Resume) => {
+                fpga::fifo_start(handle, &profile.endpoints)?;
+                *paused = false;
+            }
+            Ok(Command::Stop) | Err(TryRecvError::Disconnected) => break,
+            Err(TryRecvError::Empty) => {}
         }
 
-        image::parse_image_data(&image);
+        stream.poll(timeout)?;
+    }
+
+    log::debug!("Stopping capture");
 
-        // for transfer in transfers {
-        //unsafe { usbffi::libusb_free_transfer(transfer.lib_usb_transfer); }
-        // }
-    });
+    Ok(())
 }
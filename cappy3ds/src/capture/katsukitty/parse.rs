@@ -1,4 +1,9 @@
+use std::fmt;
+
 use bytes::BytesMut;
+use memchr::memmem;
+
+use crate::capture::ColorDepth;
 
 // 33CC 23C0 1800 0000 0000 1900 0000 0000 (then 240 pixels of image)
 // 33CC 24C0 1900 0000 0000 1A00 0000 0000 (240)
@@ -6,62 +11,382 @@ use bytes::BytesMut;
 // 33CC 26C0 1C00 0000 0000 1D00 0000 0000
 // ...
 // 33CC 2EC1 0701 0000 0000 0801 0000 0000
+//
+// The second word is the line number with 0xC000 set, a plain 0000 starts a frame. The
+// two counters always step by one within a header, which tells headers apart from pixels.
 
-pub fn split_capture_buffer(data: &BytesMut) -> (BytesMut, BytesMut, BytesMut) {
-    let mut upper_buffer = BytesMut::with_capacity(400 * 240 * 2);
-    let mut lower_buffer = BytesMut::with_capacity(320 * 240 * 2);
-    let mut sound_buffer = BytesMut::with_capacity(720 * 8 * 2);
-
-    // split apart buffer into parts
-    // todo: replace with a nicer byte stream reader
-    let mut pos = 0;
-    while pos < data.len() - 496 {
-        if pos < 81 * 496 {
-            // copy preamble audio
-            sound_buffer.extend(&data[pos..pos + 16]);
-            pos += 496;
-        } else if pos >= 81 * 496 && pos < 400 * 496 {
-            sound_buffer.extend(&data[pos..pos + 16]);
-            pos += 16;
-            lower_buffer.extend(&data[pos..pos + 480]);
-            pos += 480;
-        } else if pos == 400 * 496 {
-            lower_buffer.extend(&data[pos..pos + 480]);
-            pos += 480;
-            sound_buffer.extend(&data[pos..pos + 16]);
-            pos += 16;
-        } else if pos > 400 * 496 {
-            upper_buffer.extend(&data[pos..pos + 480]);
-            pos += 480;
-            sound_buffer.extend(&data[pos..pos + 16]);
-            pos += 16;
-        }
-    }
-
-    (upper_buffer, lower_buffer, sound_buffer)
-}
-
-pub fn rgb565_to_rgb(data: &BytesMut) -> BytesMut {
-    let mut image_buffer = BytesMut::with_capacity(data.len() / 2 * 3);
-    image_buffer.resize(data.len() / 2 * 3, 0);
+// every line starts or ends with this much audio, the line header
+pub(crate) const AUDIO_BYTES: usize = 16;
+pub(crate) const LINE_PIXELS: usize = 240;
 
-    let mut j = 0;
-    for i in (0..data.len()).step_by(2) {
-        let high = (data[i + 1] as u16) << 8;
-        let c: u16 = data[i] as u16 + high;
-        let r = (((c & 0xF800) >> 11) << 3) as u8;
-        let g = (((c & 0x7E0) >> 5) << 2) as u8;
-        let b = ((c & 0x1F) << 3) as u8;
+const LINE_MAGIC: [u8; 2] = [0x33, 0xCC];
+const LINE_FLAGS: u16 = 0xC000;
+// the card counts well under this many lines, anything above is not a header
+const MAX_LINES: u16 = 0x400;
 
-        image_buffer[j] = r;
-        j += 1;
-        image_buffer[j] = g;
-        j += 1;
-        image_buffer[j] = b;
-        j += 1;
+const LOWER_LINES: u16 = 320;
+const UPPER_LINES: u16 = 400;
+
+// lines 0..=80 only carry audio, then come the lower screen and the upper screen
+const AUDIO_LINES: u16 = 81;
+const LAST_LOWER_LINE: u16 = AUDIO_LINES + LOWER_LINES - 1;
+const FIRST_UPPER_LINE: u16 = LAST_LOWER_LINE + 1;
+// lines after the upper screen carry nothing we use
+const LAST_LINE: u16 = LAST_LOWER_LINE + UPPER_LINES;
+// from here on the pixels come before the header
+const PIXELS_FIRST_LINE: u16 = 400;
+
+/// Decoded `33CC` header in front of (or, from line 400 on, behind) every line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineHeader {
+    /// Position of the line in the frame, 0 starts a new frame
+    pub line: u16,
+    pub counters: (u16, u16),
+}
+
+impl LineHeader {
+    /// Decodes the header at the start of `data`, `None` if it isn't one.
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        let header = data.get(..AUDIO_BYTES)?;
+        if header[..2] != LINE_MAGIC {
+            return None;
+        }
+
+        let line = match u16::from_le_bytes([header[2], header[3]]) {
+            0 => 0,
+            word if word & LINE_FLAGS == LINE_FLAGS && word & !LINE_FLAGS < MAX_LINES => {
+                word & !LINE_FLAGS
+            }
+            _ => return None,
+        };
+
+        let counters = (
+            u16::from_le_bytes([header[4], header[5]]),
+            u16::from_le_bytes([header[10], header[11]]),
+        );
+        if counters.1 != counters.0.wrapping_add(1) {
+            return None;
+        }
+
+        Some(Self { line, counters })
     }
+}
 
-    image_buffer
+/// Lines that went wrong while a frame was put together.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FrameDamage {
+    /// Lines that never arrived or arrived cut short, they are left black
+    pub missing_lines: usize,
+    /// Lines that arrived more than once
+    pub duplicate_lines: usize,
+    /// Bytes between lines that belonged to no header and were thrown away
+    pub skipped_bytes: usize,
+}
+
+impl FrameDamage {
+    pub fn is_clean(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// One frame in the card's pixel format, the screens rotated as the card sends them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub upper: BytesMut,
+    pub lower: BytesMut,
+    /// The raw 16 byte line headers, one per line, zeroed for missing lines
+    ///
+    /// This is not decoded sound, it holds the `33CC` magic and the line counters.
+    pub audio: BytesMut,
+    pub damage: FrameDamage,
+}
+
+/// Why `split_frame` could not return a whole frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// The data holds no frame start header
+    NoFrameStart,
+    /// Lines were missing, repeated or cut short, the frame holds everything that could be placed
+    Incomplete(Frame),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::NoFrameStart => write!(f, "no frame start found"),
+            ParseError::Incomplete(frame) => write!(
+                f,
+                "incomplete frame: {} lines missing, {} repeated, {} bytes skipped",
+                frame.damage.missing_lines,
+                frame.damage.duplicate_lines,
+                frame.damage.skipped_bytes
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Splits the first frame in `data` into screens and audio, for buffers cut at frame starts.
+///
+/// Anything after the next frame start is ignored. This never panics, whatever the input.
+pub fn split_frame(data: &[u8], color_depth: ColorDepth) -> Result<Frame, ParseError> {
+    let mut parser = LineParser::new(color_depth);
+
+    let mut first = None;
+    parser.push(data, |frame| {
+        first.get_or_insert(frame);
+    });
+
+    let frame = first
+        .or_else(|| parser.finish())
+        .ok_or(ParseError::NoFrameStart)?;
+
+    if frame.damage.is_clean() {
+        Ok(frame)
+    } else {
+        Err(ParseError::Incomplete(frame))
+    }
+}
+
+struct FrameBuilder {
+    frame: Frame,
+    // highest line placed so far
+    last_line: u16,
+}
+
+impl FrameBuilder {
+    fn new(pixel_bytes: usize) -> Self {
+        Self {
+            frame: Frame {
+                upper: BytesMut::zeroed(UPPER_LINES as usize * pixel_bytes),
+                lower: BytesMut::zeroed(LOWER_LINES as usize * pixel_bytes),
+                audio: BytesMut::with_capacity((LAST_LINE as usize + 1) * AUDIO_BYTES),
+                damage: FrameDamage::default(),
+            },
+            last_line: 0,
+        }
+    }
+
+    // checks `header` against the lines before it, false if it is a repeat
+    fn add_header(&mut self, header: &[u8], line: u16) -> bool {
+        let expected = if self.frame.audio.is_empty() {
+            0
+        } else {
+            self.last_line + 1
+        };
+
+        if line < expected {
+            self.frame.damage.duplicate_lines += 1;
+            return false;
+        }
+        if line > LAST_LINE {
+            return true;
+        }
+
+        // keep the audio in step with the lines that went missing
+        let missing = (line - expected) as usize;
+        self.frame.damage.missing_lines += missing;
+        self.frame
+            .audio
+            .resize(self.frame.audio.len() + missing * AUDIO_BYTES, 0);
+        self.frame.audio.extend_from_slice(header);
+        self.last_line = line;
+
+        true
+    }
+
+    fn add_pixels(&mut self, line: u16, pixels: &[u8]) {
+        let (buffer, row) = match line {
+            AUDIO_LINES..=LAST_LOWER_LINE => (&mut self.frame.lower, line - AUDIO_LINES),
+            FIRST_UPPER_LINE..=LAST_LINE => (&mut self.frame.upper, line - FIRST_UPPER_LINE),
+            _ => return,
+        };
+
+        let start = row as usize * pixels.len();
+        buffer[start..start + pixels.len()].copy_from_slice(pixels);
+    }
+
+    fn lose_pixels(&mut self, line: u16) {
+        if (AUDIO_LINES..=LAST_LINE).contains(&line) {
+            self.frame.damage.missing_lines += 1;
+        }
+    }
+
+    fn finish(mut self) -> Frame {
+        // lines cut off at the end of the frame
+        let received = self.frame.audio.len() / AUDIO_BYTES;
+        self.frame.damage.missing_lines += LAST_LINE as usize + 1 - received;
+        self.frame
+            .audio
+            .resize((LAST_LINE as usize + 1) * AUDIO_BYTES, 0);
+        self.frame
+    }
+}
+
+/// Splits the card's byte stream into frames by their line headers.
+///
+/// Lines are placed by the number in their header, so lost or short transfers only cost the
+/// lines they carried. After damage the parser looks for the next valid header and carries
+/// on from there.
+pub struct LineParser {
+    color_depth: ColorDepth,
+    // everything after the last header seen
+    pending: BytesMut,
+    // line of the last header if its pixels follow it, and whether they are wanted
+    pixels_after: Option<(u16, bool)>,
+    frame: Option<FrameBuilder>,
+}
+
+impl LineParser {
+    pub fn new(color_depth: ColorDepth) -> Self {
+        Self {
+            color_depth,
+            pending: BytesMut::new(),
+            pixels_after: None,
+            frame: None,
+        }
+    }
+
+    fn pixel_bytes(&self) -> usize {
+        LINE_PIXELS * self.color_depth.bytes_per_pixel()
+    }
+
+    /// Adds the contents of one transfer, handing every frame it completes to `on_frame`.
+    pub fn push<C: FnMut(Frame)>(&mut self, data: &[u8], mut on_frame: C) {
+        self.pending.extend_from_slice(data);
+
+        while let Some((pos, header)) = self.next_header() {
+            let gap = self.pending.split_to(pos);
+            let header_bytes = self.pending.split_to(AUDIO_BYTES);
+            self.add_line(&gap, &header_bytes, header, &mut on_frame);
+        }
+
+        // a header is never more than two lines of pixels away, around line 400
+        let keep = 2 * self.pixel_bytes() + AUDIO_BYTES - 1;
+        if self.pending.len() > keep {
+            if let Some((line, true)) = self.pixels_after.take() {
+                self.lose_pixels(line);
+            }
+            let skipped = self.pending.len() - keep;
+            let _ = self.pending.split_to(skipped);
+            self.skip(skipped);
+        }
+    }
+
+    /// Ends the stream, returning the frame that was still being put together.
+    pub fn finish(mut self) -> Option<Frame> {
+        if let Some((line, wanted)) = self.pixels_after.take() {
+            let pixel_bytes = self.pixel_bytes();
+            match (self.pending.get(..pixel_bytes), &mut self.frame, wanted) {
+                (Some(pixels), Some(frame), true) => frame.add_pixels(line, pixels),
+                (None, Some(frame), true) => frame.lose_pixels(line),
+                _ => {}
+            }
+            let _ = self.pending.split_to(pixel_bytes.min(self.pending.len()));
+        }
+
+        // the start of a line that never got its header
+        self.skip(self.pending.len());
+
+        self.frame.take().map(FrameBuilder::finish)
+    }
+
+    // position and contents of the next header in `pending`
+    fn next_header(&self) -> Option<(usize, LineHeader)> {
+        // usually it is right where the layout says
+        if let Some((line, _)) = self.pixels_after {
+            let expected = match line + 1 {
+                PIXELS_FIRST_LINE => 2 * self.pixel_bytes(),
+                _ => self.pixel_bytes(),
+            };
+            if let Some(header) = self.pending.get(expected..).and_then(LineHeader::decode) {
+                return Some((expected, header));
+            }
+        }
+
+        memmem::find_iter(&self.pending, &LINE_MAGIC)
+            .find_map(|pos| LineHeader::decode(&self.pending[pos..]).map(|header| (pos, header)))
+    }
+
+    // `gap` is everything between the previous header and this one
+    fn add_line<C: FnMut(Frame)>(
+        &mut self,
+        mut gap: &[u8],
+        header_bytes: &[u8],
+        header: LineHeader,
+        on_frame: &mut C,
+    ) {
+        let pixel_bytes = self.pixel_bytes();
+
+        // pixels of the previous line
+        if let Some((line, wanted)) = self.pixels_after.take() {
+            if gap.len() < pixel_bytes {
+                if wanted {
+                    self.lose_pixels(line);
+                }
+            } else {
+                if let (Some(frame), true) = (&mut self.frame, wanted) {
+                    frame.add_pixels(line, &gap[..pixel_bytes]);
+                }
+                gap = &gap[pixel_bytes..];
+            }
+        }
+
+        // a frame start, or a jump back into the audio lines when the start got lost
+        let new_frame = header.line == 0
+            || (header.line < AUDIO_LINES
+                && self
+                    .frame
+                    .as_ref()
+                    .is_some_and(|frame| frame.last_line >= AUDIO_LINES));
+        if new_frame {
+            if let Some(frame) = self.frame.take() {
+                on_frame(frame.finish());
+            }
+            self.frame = Some(FrameBuilder::new(pixel_bytes));
+        }
+
+        let Some(frame) = self.frame.as_mut() else {
+            // still waiting for the first frame start
+            return;
+        };
+        let wanted = frame.add_header(header_bytes, header.line);
+
+        if header.line < PIXELS_FIRST_LINE {
+            self.pixels_after = Some((header.line, wanted));
+        } else if let Some(rest) = gap.len().checked_sub(pixel_bytes) {
+            // the pixels came in front of the header
+            if wanted {
+                frame.add_pixels(header.line, &gap[rest..]);
+            }
+            gap = &gap[..rest];
+        } else if wanted {
+            frame.lose_pixels(header.line);
+        }
+
+        self.skip(gap.len());
+    }
+
+    fn lose_pixels(&mut self, line: u16) {
+        if let Some(frame) = &mut self.frame {
+            frame.lose_pixels(line);
+        }
+    }
+
+    // bytes outside of any frame aren't damage, we just haven't found a frame start yet
+    fn skip(&mut self, bytes: usize) {
+        if let Some(frame) = &mut self.frame {
+            frame.frame.damage.skipped_bytes += bytes;
+        }
+    }
+}
+
+pub fn to_rgba(data: &BytesMut, color_depth: ColorDepth) -> BytesMut {
+    match color_depth {
+        ColorDepth::Rgb565 => rgb565_to_rgba(data),
+        ColorDepth::Rgb888 => rgb888_to_rgba(data),
+    }
 }
 
 pub fn rgb565_to_rgba(data: &BytesMut) -> BytesMut {
@@ -88,3 +413,309 @@ pub fn rgb565_to_rgba(data: &BytesMut) -> BytesMut {
 
     image_buffer
 }
+
+// assumes the card sends red first, like the channel order of RGB565
+pub fn rgb888_to_rgba(data: &[u8]) -> BytesMut {
+    let mut image_buffer = BytesMut::with_capacity(data.len() / 3 * 4);
+
+    for pixel in data.chunks_exact(3) {
+        image_buffer.extend_from_slice(&[pixel[0], pixel[1], pixel[2], 0]);
+    }
+
+    image_buffer
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PIXEL_BYTES: usize = LINE_PIXELS * 2;
+
+    fn header(line: u16) -> Vec<u8> {
+        let mut header = vec![0; AUDIO_BYTES];
+        header[..2].copy_from_slice(&LINE_MAGIC);
+        if line > 0 {
+            header[2..4].copy_from_slice(&(LINE_FLAGS | line).to_le_bytes());
+        }
+        header[4..6].copy_from_slice(&(line * 2).to_le_bytes());
+        header[10..12].copy_from_slice(&(line * 2 + 1).to_le_bytes());
+        header
+    }
+
+    // every pixel of a line is its line number, so misplaced rows show up
+    fn line(line: u16) -> Vec<u8> {
+        let pixels = line.to_le_bytes().repeat(LINE_PIXELS);
+        match line {
+            line if line < PIXELS_FIRST_LINE => [header(line), pixels].concat(),
+            line => [pixels, header(line)].concat(),
+        }
+    }
+
+    fn lines(range: std::ops::RangeInclusive<u16>) -> Vec<u8> {
+        range.flat_map(line).collect()
+    }
+
+    fn parse(data: &[u8], chunk: usize) -> Vec<Frame> {
+        let mut parser = LineParser::new(ColorDepth::Rgb565);
+        let mut frames = Vec::new();
+        for transfer in data.chunks(chunk) {
+            parser.push(transfer, |frame| frames.push(frame));
+        }
+        frames
+    }
+
+    fn row(buffer: &[u8], row: usize) -> &[u8] {
+        &buffer[row * PIXEL_BYTES..(row + 1) * PIXEL_BYTES]
+    }
+
+    fn assert_rows(frame: &Frame, missing: &[u16]) {
+        for line in AUDIO_LINES..=LAST_LINE {
+            let pixels = match line {
+                line if line <= LAST_LOWER_LINE => row(&frame.lower, (line - AUDIO_LINES) as usize),
+                line => row(&frame.upper, (line - FIRST_UPPER_LINE) as usize),
+            };
+            let value = if missing.contains(&line) { 0 } else { line };
+            assert_eq!(
+                pixels,
+                &value.to_le_bytes().repeat(LINE_PIXELS)[..],
+                "line {}",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn header_decodes() {
+        let decoded = LineHeader::decode(&[
+            0x33, 0xCC, 0x23, 0xC0, 0x18, 0x00, 0x00, 0x00, 0x00, 0x00, 0x19, 0x00, 0x00, 0x00,
+            0x00, 0x00,
+        ]);
+        assert_eq!(
+            decoded,
+            Some(LineHeader {
+                line: 0x23,
+                counters: (0x18, 0x19)
+            })
+        );
+
+        assert_eq!(LineHeader::decode(&header(5)[..8]), None);
+        let mut bad_counter = header(5);
+        bad_counter[10] = 0;
+        assert_eq!(LineHeader::decode(&bad_counter), None);
+    }
+
+    #[test]
+    fn frames_split_at_any_transfer_size() {
+        let stream = [lines(0..=801), lines(0..=801), lines(0..=3)].concat();
+
+        for chunk in [1, 7, 496, 16384] {
+            let frames = parse(&stream, chunk);
+            assert_eq!(frames.len(), 2);
+
+            for frame in &frames {
+                assert!(frame.damage.is_clean(), "{:?}", frame.damage);
+                assert_eq!(frame.audio.len(), (LAST_LINE as usize + 1) * AUDIO_BYTES);
+                assert_rows(frame, &[]);
+            }
+        }
+    }
+
+    #[test]
+    fn short_transfer_costs_only_its_line() {
+        let mut first = lines(0..=801);
+        // part of line 100's pixels never arrive
+        let start = 100 * (AUDIO_BYTES + PIXEL_BYTES) + AUDIO_BYTES + 10;
+        first.drain(start..start + 100);
+
+        let frames = parse(&[first, lines(0..=801), lines(0..=0)].concat(), 512);
+
+        assert_eq!(frames[0].damage.missing_lines, 1);
+        assert_eq!(frames[0].lower.len(), LOWER_LINES as usize * PIXEL_BYTES);
+        assert_rows(&frames[0], &[100]);
+        assert!(frames[1].damage.is_clean());
+    }
+
+    #[test]
+    fn missing_and_duplicate_lines_are_reported() {
+        let stream = [lines(0..=200), line(200), lines(203..=801), lines(0..=0)].concat();
+
+        let frames = parse(&stream, 1000);
+
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].damage.missing_lines, 2);
+        assert_eq!(frames[0].damage.duplicate_lines, 1);
+        assert_eq!(
+            frames[0].audio.len(),
+            (LAST_LINE as usize + 1) * AUDIO_BYTES
+        );
+        assert_rows(&frames[0], &[201, 202]);
+    }
+
+    #[test]
+    fn garbage_between_lines_is_skipped() {
+        let stream = [
+            lines(0..=450),
+            vec![0x33; 37],
+            lines(451..=801),
+            lines(0..=0),
+        ]
+        .concat();
+
+        let frames = parse(&stream, 333);
+
+        // line 451's pixels sit in front of its header, the garbage before them is dropped
+        assert_eq!(frames[0].damage.skipped_bytes, 37);
+        assert_eq!(frames[0].damage.missing_lines, 0);
+        assert_rows(&frames[0], &[]);
+    }
+
+    #[test]
+    fn lost_frame_start_still_splits_frames() {
+        let stream = [lines(0..=801), lines(1..=801), lines(0..=0)].concat();
+
+        let frames = parse(&stream, 4096);
+
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[1].damage.missing_lines, 1);
+        assert_rows(&frames[1], &[]);
+    }
+
+    #[test]
+    fn split_frame_reports_what_it_could_place() {
+        let frame = split_frame(&lines(0..=801), ColorDepth::Rgb565).unwrap();
+        assert_rows(&frame, &[]);
+
+        assert_eq!(
+            split_frame(&[], ColorDepth::Rgb565),
+            Err(ParseError::NoFrameStart)
+        );
+        assert_eq!(
+            split_frame(&lines(5..=20), ColorDepth::Rgb565),
+            Err(ParseError::NoFrameStart)
+        );
+
+        // cut off half way through the upper screen
+        match split_frame(&lines(0..=600), ColorDepth::Rgb565) {
+            Err(ParseError::Incomplete(frame)) => {
+                assert_eq!(frame.damage.missing_lines, (LAST_LINE - 600) as usize);
+                assert_rows(&frame, &(601..=LAST_LINE).collect::<Vec<_>>());
+            }
+            other => panic!("{:?}", other.map(|frame| frame.damage)),
+        }
+    }
+
+    // xorshift, enough randomness to shake out the edge cases without a dependency
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+
+        fn bytes(&mut self, len: usize) -> Vec<u8> {
+            (0..len).map(|_| self.next() as u8).collect()
+        }
+    }
+
+    // random bytes, random headers and runs of valid lines, in random order
+    fn random_stream(rng: &mut Rng) -> Vec<u8> {
+        let mut stream = Vec::new();
+
+        for _ in 0..rng.below(40) {
+            match rng.below(4) {
+                0 => {
+                    let len = rng.below(2 * PIXEL_BYTES);
+                    stream.extend(rng.bytes(len));
+                }
+                1 => stream.extend(header(rng.below(MAX_LINES as usize) as u16)),
+                2 => {
+                    let start = rng.below(LAST_LINE as usize + 2) as u16;
+                    let end = (start + rng.below(40) as u16).min(LAST_LINE + 1);
+                    stream.extend(lines(start..=end));
+                }
+                _ => {
+                    // a valid header cut short or with its pixels cut short
+                    let line = line(rng.below(LAST_LINE as usize + 2) as u16);
+                    stream.extend(&line[..rng.below(line.len())]);
+                }
+            }
+        }
+
+        stream
+    }
+
+    fn assert_whole(frame: &Frame, color_depth: ColorDepth) {
+        let pixel_bytes = LINE_PIXELS * color_depth.bytes_per_pixel();
+        assert_eq!(frame.upper.len(), UPPER_LINES as usize * pixel_bytes);
+        assert_eq!(frame.lower.len(), LOWER_LINES as usize * pixel_bytes);
+        assert_eq!(frame.audio.len(), (LAST_LINE as usize + 1) * AUDIO_BYTES);
+    }
+
+    #[test]
+    fn random_streams_never_panic() {
+        let mut rng = Rng(0x5eed_cafe_f00d_d00d);
+
+        for _ in 0..500 {
+            let stream = random_stream(&mut rng);
+            let color_depth = match rng.below(2) {
+                0 => ColorDepth::Rgb565,
+                _ => ColorDepth::Rgb888,
+            };
+
+            match split_frame(&stream, color_depth) {
+                Ok(frame) | Err(ParseError::Incomplete(frame)) => assert_whole(&frame, color_depth),
+                Err(ParseError::NoFrameStart) => {}
+            }
+
+            let mut parser = LineParser::new(color_depth);
+            let mut rest = &stream[..];
+            while !rest.is_empty() {
+                let (transfer, tail) = rest.split_at(rng.below(rest.len()) + 1);
+                parser.push(transfer, |frame| assert_whole(&frame, color_depth));
+                rest = tail;
+            }
+            if let Some(frame) = parser.finish() {
+                assert_whole(&frame, color_depth);
+            }
+        }
+    }
+
+    #[test]
+    fn damaged_frames_keep_their_shape() {
+        let mut rng = Rng(0x0dd_ba11);
+        let clean = [lines(0..=801), lines(0..=0)].concat();
+
+        for _ in 0..50 {
+            let mut stream = clean.clone();
+            for _ in 0..rng.below(8) + 1 {
+                let pos = rng.below(stream.len());
+                match rng.below(3) {
+                    0 => {
+                        let end = (pos + rng.below(2000)).min(stream.len());
+                        stream.drain(pos..end);
+                    }
+                    1 => {
+                        let len = rng.below(2000);
+                        let garbage = rng.bytes(len);
+                        stream.splice(pos..pos, garbage);
+                    }
+                    _ => stream[pos] ^= 1 << rng.below(8),
+                }
+            }
+
+            match split_frame(&stream, ColorDepth::Rgb565) {
+                Ok(frame) | Err(ParseError::Incomplete(frame)) => {
+                    assert_whole(&frame, ColorDepth::Rgb565)
+                }
+                Err(ParseError::NoFrameStart) => {}
+            }
+        }
+    }
+}
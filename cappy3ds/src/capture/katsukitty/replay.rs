@@ -0,0 +1,161 @@
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::TryRecvError;
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+use super::{card_capabilities, open_recorder, CaptureHandler};
+use crate::capture::bus::{UsbBus, UsbDescription};
+use crate::capture::devices::{DeviceProfile, Endpoints, Quirks};
+use crate::capture::transfer::TransferConfig;
+use crate::capture::{CaptureBackend, ColorDepth, ConnectConfig, DataCallback, POLL_INTERVAL};
+use crate::recording::RecordingReader;
+use crate::session::{CaptureSession, Command};
+use crate::{Capabilities, DeviceInfo, Error};
+
+/// How fast a replay hands out its transfers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReplaySpeed {
+    /// With the gaps they were recorded with
+    #[default]
+    RealTime,
+    /// Back to back, for parser work and tests
+    Unthrottled,
+}
+
+/// Plays a Katsukity USB recording through the same parser and callback as a live card.
+pub struct KatsukityReplay {
+    path: PathBuf,
+    speed: ReplaySpeed,
+    profile: DeviceProfile,
+    info: DeviceInfo,
+}
+
+impl KatsukityReplay {
+    /// Opens a file written by `RecordingWriter`, checking its header.
+    pub fn open<P: AsRef<Path>>(path: P, speed: ReplaySpeed) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let reader = RecordingReader::open(path)?;
+
+        let profile = DeviceProfile {
+            name: format!("recording {}", path.display()),
+            backend: "Replay".to_string(),
+            vendor_id: 0,
+            product_id: 0,
+            product: None,
+            interfaces: Vec::new(),
+            endpoints: Endpoints {
+                data: reader.endpoint(),
+                ..Endpoints::default()
+            },
+            color_depth: reader.color_depth(),
+            quirks: Quirks::default(),
+        };
+
+        let description = UsbDescription {
+            product: path.file_name().map(|name| name.to_string_lossy().into_owned()),
+            ..UsbDescription::default()
+        };
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            speed,
+            profile,
+            info: DeviceInfo::new(&description, "Replay", Vec::new()),
+        })
+    }
+
+    /// The recording as a device, the model is the file name.
+    pub fn device_info(&self) -> &DeviceInfo {
+        &self.info
+    }
+}
+
+impl CaptureBackend for KatsukityReplay {
+    fn name(&self) -> &'static str {
+        "Replay"
+    }
+
+    fn profile(&self) -> &DeviceProfile {
+        &self.profile
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        match self.profile.color_depth {
+            ColorDepth::Rgb565 => card_capabilities(&[ColorDepth::Rgb565]),
+            ColorDepth::Rgb888 => card_capabilities(&[ColorDepth::Rgb888]),
+        }
+    }
+
+    fn matches(&self, _vendor_id: u16, _product_id: u16) -> bool {
+        false
+    }
+
+    // nothing on the bus to set up, the file was checked when it was opened
+    fn connect(&mut self, _bus: &Arc<dyn UsbBus>, _config: &ConnectConfig) -> Result<DeviceInfo, Error> {
+        Ok(self.info.clone())
+    }
+
+    fn start(
+        &self,
+        data_callback: DataCallback,
+        _transfer_config: TransferConfig,
+        _auto_recover: bool,
+        record_to: Option<&Path>,
+    ) -> Result<CaptureSession, Error> {
+        // every session starts from the top of the file
+        let mut reader = RecordingReader::open(&self.path)?;
+        let speed = self.speed;
+
+        let color_depth = reader.color_depth();
+        let recorder = open_recorder(record_to, color_depth, &self.profile)?;
+
+        Ok(CaptureSession::spawn(move |commands, events| {
+            log::info!("Replaying a {:?} recording", color_depth);
+
+            let mut handler = CaptureHandler::new(data_callback, color_depth, recorder).with_events(events);
+            let mut started = Instant::now();
+            let mut paused_at = None;
+
+            while let Some(transfer) = reader.next_transfer()? {
+                // wait out pauses and, in real time, the gap before this transfer
+                loop {
+                    match commands.try_recv() {
+                        Ok(Command::Pause) => {
+                            paused_at.get_or_insert_with(Instant::now);
+                        }
+                        Ok(Command::Resume) => {
+                            if let Some(paused_at) = paused_at.take() {
+                                started += paused_at.elapsed();
+                            }
+                        }
+                        Ok(Command::Stop) | Err(TryRecvError::Disconnected) => return Ok(()),
+                        Err(TryRecvError::Empty) => {}
+                    }
+
+                    let wait = match (paused_at, speed) {
+                        (Some(_), _) => POLL_INTERVAL,
+                        (None, ReplaySpeed::RealTime) => (started + transfer.timestamp)
+                            .saturating_duration_since(Instant::now()),
+                        (None, ReplaySpeed::Unthrottled) => break,
+                    };
+
+                    if wait.is_zero() {
+                        break;
+                    }
+                    thread::sleep(wait.min(POLL_INTERVAL));
+                }
+
+                handler.push(&transfer.data);
+            }
+
+            log::info!("End of recording");
+
+            Ok(())
+        }))
+    }
+
+    fn stop(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
@@ -0,0 +1,586 @@
+use std::collections::VecDeque;
+use std::sync::mpsc::SyncSender;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+use super::command::FpgaCommand;
+use super::fpga::{FPGA_BANNER, FPGA_EMPTY_RESPONSE};
+use super::parse::{AUDIO_BYTES, LINE_PIXELS};
+use crate::capture::bus::{OpenDevice, UsbBus, UsbDescription, UsbDevice, UsbSelector};
+use crate::capture::fx2::{CPUCS, FIRMWARE_LOAD};
+use crate::capture::transfer::TransferConfig;
+use crate::capture::transport::{BulkStream, StreamHandler, Transport};
+use crate::capture::ColorDepth;
+use crate::session::CaptureEvent;
+use crate::{Error, EEPROM_SIZE};
+
+const VENDOR_ID: u16 = 0x0752;
+const FX2_PRODUCT_ID: u16 = 0x8613;
+const CARD_PRODUCT_ID: u16 = 0xf2c0;
+
+const COMMAND_ENDPOINT: u8 = 0x01;
+const RESPONSE_ENDPOINT: u8 = 0x81;
+const DATA_ENDPOINT: u8 = 0x82;
+
+const PRODUCT: &str = "Simulated Katsukity";
+
+// longest a read with nothing to return pretends to wait
+const IDLE_WAIT: Duration = Duration::from_millis(10);
+// breather between bursts of frame data so a capture loop doesn't eat a whole core
+const STREAM_WAIT: Duration = Duration::from_millis(1);
+
+// the line layout `parse::LineParser` expects: audio only lines, then the lower
+// screen with the audio in front, then from line 400 on the pixels in front of the audio
+const AUDIO_LINES: usize = 81;
+const PIXELS_FIRST_LINE: usize = 400;
+// one extra line so the last upper screen line is not the end of the frame
+const FRAME_LINES: usize = AUDIO_LINES + 320 + 400 + 1;
+
+// second byte of the middle 0x71 record, see `fpga::mode_packets`
+const RGB888_MODE: u8 = 0x4f;
+
+/// Colour of every pixel the simulated card sends for the upper screen, as RGBA.
+pub const SIM_UPPER_COLOR: [u8; 4] = [0xf8, 0x00, 0x00, 0x00];
+/// Colour of every pixel the simulated card sends for the lower screen, as RGBA.
+pub const SIM_LOWER_COLOR: [u8; 4] = [0x00, 0x00, 0xf8, 0x00];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Fpga {
+    Empty,
+    /// Bitstream bytes are arriving, the 0x71 records may have picked a format
+    Loading(Option<ColorDepth>),
+    Configured(ColorDepth),
+}
+
+#[derive(Debug)]
+struct Card {
+    plugged: bool,
+    // bumped whenever the card drops off the bus, handles from before stop working
+    generation: u64,
+    firmware_running: bool,
+    ram: Vec<u8>,
+    cpu_reset: bool,
+    fpga: Fpga,
+    responses: VecDeque<Vec<u8>>,
+    streaming: bool,
+    eeprom: Vec<u8>,
+    eeprom_write_protected: bool,
+    frames_sent: u64,
+    firmware_uploads: usize,
+    dropped_transfers: usize,
+    stall: bool,
+}
+
+impl Card {
+    fn new(eeprom: Vec<u8>) -> Self {
+        Self {
+            plugged: true,
+            generation: 0,
+            firmware_running: false,
+            ram: vec![0; 0x10000],
+            cpu_reset: false,
+            fpga: Fpga::Empty,
+            responses: VecDeque::new(),
+            streaming: false,
+            eeprom,
+            eeprom_write_protected: false,
+            frames_sent: 0,
+            firmware_uploads: 0,
+            dropped_transfers: 0,
+            stall: false,
+        }
+    }
+
+    fn product_id(&self) -> u16 {
+        if self.firmware_running {
+            CARD_PRODUCT_ID
+        } else {
+            FX2_PRODUCT_ID
+        }
+    }
+
+    // every re-enumeration hands out the next address, like a real hub would
+    fn listed(&self) -> Option<UsbDevice> {
+        self.plugged.then(|| UsbDevice {
+            vendor_id: VENDOR_ID,
+            product_id: self.product_id(),
+            bus_number: 1,
+            address: (self.generation % 127) as u8 + 1,
+        })
+    }
+
+    // power is lost, only the EEPROM survives
+    fn detach(&mut self) {
+        self.generation += 1;
+        self.firmware_running = false;
+        self.cpu_reset = false;
+        self.fpga = Fpga::Empty;
+        self.responses.clear();
+        self.streaming = false;
+    }
+
+    fn fx2_write(&mut self, address: u16, data: &[u8]) {
+        if address == CPUCS {
+            let reset = data.first().is_some_and(|value| value & 1 != 0);
+
+            // the firmware starts, drops off the bus and comes back as the card
+            if self.cpu_reset && !reset {
+                self.generation += 1;
+                self.firmware_running = true;
+                self.firmware_uploads += 1;
+            }
+            self.cpu_reset = reset;
+            return;
+        }
+
+        let start = address as usize;
+        let end = (start + data.len()).min(self.ram.len());
+        self.ram[start..end].copy_from_slice(&data[..end - start]);
+    }
+
+    fn fx2_read(&self, address: u16, buf: &mut [u8]) -> usize {
+        if address == CPUCS {
+            buf[0] = self.cpu_reset as u8;
+            return 1;
+        }
+
+        let start = address as usize;
+        let end = (start + buf.len()).min(self.ram.len());
+        buf[..end - start].copy_from_slice(&self.ram[start..end]);
+        end - start
+    }
+
+    fn command(&mut self, command: FpgaCommand) {
+        match command {
+            FpgaCommand::EepromRead { offset, len } => {
+                let start = (offset as usize).min(self.eeprom.len());
+                let end = (start + len as usize).min(self.eeprom.len());
+                self.responses.push_back(self.eeprom[start..end].to_vec());
+            }
+            FpgaCommand::EepromWrite { .. } if self.eeprom_write_protected => {}
+            FpgaCommand::EepromWrite { offset, data } => {
+                let start = (offset as usize).min(self.eeprom.len());
+                let end = (start + data.len()).min(self.eeprom.len());
+                self.eeprom[start..end].copy_from_slice(&data[..end - start]);
+            }
+            FpgaCommand::Bitstream(_) if !matches!(self.fpga, Fpga::Loading(_)) => {
+                self.fpga = Fpga::Loading(None);
+                self.streaming = false;
+            }
+            FpgaCommand::VideoMode(records) => {
+                if let (Fpga::Loading(_), Some(record)) = (self.fpga, records.get(1)) {
+                    self.fpga = Fpga::Loading(Some(if record[2] == RGB888_MODE {
+                        ColorDepth::Rgb888
+                    } else {
+                        ColorDepth::Rgb565
+                    }));
+                }
+            }
+            // the last register block after the bitstream, the FPGA comes up
+            FpgaCommand::WriteRegisters(_) => {
+                if let Fpga::Loading(Some(color_depth)) = self.fpga {
+                    self.fpga = Fpga::Configured(color_depth);
+                    self.responses.push_back(FPGA_BANNER.to_vec());
+                }
+            }
+            FpgaCommand::FifoStart => {
+                self.streaming = matches!(self.fpga, Fpga::Configured(_));
+            }
+            FpgaCommand::FifoStop => self.streaming = false,
+            _ => {}
+        }
+    }
+}
+
+/// A Katsukity card that lives in memory, for running the whole connect and capture path
+/// without hardware.
+///
+/// It starts out as a bare FX2 (0752:8613). Once firmware has been written through the 0xa0
+/// vendor request and CPUCS released it re-enumerates as 0752:f2c0, answers EEPROM reads,
+/// takes the FPGA setup and bitstream and replies with the `(C)tan` banner. After
+/// `fifo_start` it streams frames on endpoint 0x82 in the layout `parse.rs` reads, every
+/// upper screen pixel `SIM_UPPER_COLOR` and every lower one `SIM_LOWER_COLOR`.
+///
+/// The simulator is its own `UsbBus`. Clones share the same card, keep one around to inject
+/// faults while a `Cappy3ds` owns the other.
+#[derive(Debug, Clone)]
+pub struct SimulatedKatsukity {
+    card: Arc<Mutex<Card>>,
+}
+
+impl Default for SimulatedKatsukity {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SimulatedKatsukity {
+    /// A freshly plugged in card with a blank EEPROM apart from a name tag.
+    pub fn new() -> Self {
+        let mut eeprom = vec![0xff; EEPROM_SIZE];
+        eeprom[..PRODUCT.len()].copy_from_slice(PRODUCT.as_bytes());
+
+        Self::with_eeprom(eeprom)
+    }
+
+    /// A freshly plugged in card with these EEPROM contents, padded or cut to `EEPROM_SIZE`.
+    pub fn with_eeprom(mut eeprom: Vec<u8>) -> Self {
+        eeprom.resize(EEPROM_SIZE, 0xff);
+
+        Self {
+            card: Arc::new(Mutex::new(Card::new(eeprom))),
+        }
+    }
+
+    fn card(&self) -> MutexGuard<'_, Card> {
+        self.card.lock().unwrap()
+    }
+
+    /// VID/PID the card currently shows, `None` while unplugged.
+    pub fn usb_id(&self) -> Option<(u16, u16)> {
+        let card = self.card();
+        card.plugged.then(|| (VENDOR_ID, card.product_id()))
+    }
+
+    /// Pixel format the FPGA was configured for, `None` while it has no bitstream.
+    pub fn color_depth(&self) -> Option<ColorDepth> {
+        match self.card().fpga {
+            Fpga::Configured(color_depth) => Some(color_depth),
+            _ => None,
+        }
+    }
+
+    /// True between `fifo_start` and `fifo_stop`.
+    pub fn is_streaming(&self) -> bool {
+        self.card().streaming
+    }
+
+    /// How many times FX2 firmware was uploaded and started.
+    pub fn firmware_uploads(&self) -> usize {
+        self.card().firmware_uploads
+    }
+
+    /// Frames generated for the data endpoint so far.
+    pub fn frames_sent(&self) -> u64 {
+        self.card().frames_sent
+    }
+
+    pub fn eeprom(&self) -> Vec<u8> {
+        self.card().eeprom.clone()
+    }
+
+    /// Loses the next `count` transfers on the data endpoint without telling anyone.
+    pub fn drop_transfers(&self, count: usize) {
+        self.card().dropped_transfers += count;
+    }
+
+    /// Ignores EEPROM writes from now on, like a card with its write protect pin set.
+    pub fn protect_eeprom(&self) {
+        self.card().eeprom_write_protected = true;
+    }
+
+    /// Stalls the next transfer on the data endpoint, its data is lost.
+    pub fn stall(&self) {
+        self.card().stall = true;
+    }
+
+    /// Pulls the card off the bus, every open handle fails with `NoDevice` from now on.
+    pub fn unplug(&self) {
+        let mut card = self.card();
+        card.plugged = false;
+        card.detach();
+    }
+
+    /// Plugs the card back in. It lost power, so it is a bare FX2 again.
+    pub fn plug_in(&self) {
+        self.card().plugged = true;
+    }
+}
+
+impl UsbBus for SimulatedKatsukity {
+    fn devices(&self) -> Result<Vec<UsbDevice>, Error> {
+        Ok(self.card().listed().into_iter().collect())
+    }
+
+    fn open(&self, selector: UsbSelector) -> Result<Option<OpenDevice>, Error> {
+        let card = self.card();
+
+        let device = match card.listed() {
+            Some(device) if selector.matches(&device) => device,
+            _ => return Ok(None),
+        };
+
+        let handle = SimHandle {
+            card: self.card.clone(),
+            generation: card.generation,
+        };
+
+        Ok(Some(OpenDevice {
+            handle: Box::new(handle),
+            description: UsbDescription {
+                vendor_id: device.vendor_id,
+                product_id: device.product_id,
+                device_version: (0, 0, 1),
+                // the bare FX2 has no strings
+                product: card.firmware_running.then(|| PRODUCT.to_string()),
+                serial: None,
+            },
+        }))
+    }
+}
+
+// one open of the card, dead once the card re-enumerates or is unplugged
+struct SimHandle {
+    card: Arc<Mutex<Card>>,
+    generation: u64,
+}
+
+impl SimHandle {
+    fn card(&self) -> rusb::Result<MutexGuard<'_, Card>> {
+        let card = self.card.lock().unwrap();
+
+        if !card.plugged || card.generation != self.generation {
+            return Err(rusb::Error::NoDevice);
+        }
+
+        Ok(card)
+    }
+}
+
+impl Transport for SimHandle {
+    fn set_active_configuration(&self, _config: u8) -> rusb::Result<()> {
+        self.card().map(|_| ())
+    }
+
+    fn claim_interface(&self, _iface: u8) -> rusb::Result<()> {
+        self.card().map(|_| ())
+    }
+
+    fn release_interface(&self, _iface: u8) -> rusb::Result<()> {
+        self.card().map(|_| ())
+    }
+
+    fn clear_halt(&self, _endpoint: u8) -> rusb::Result<()> {
+        self.card().map(|_| ())
+    }
+
+    fn read_control(
+        &self,
+        _request_type: u8,
+        request: u8,
+        value: u16,
+        _index: u16,
+        buf: &mut [u8],
+        _timeout: Duration,
+    ) -> rusb::Result<usize> {
+        let card = self.card()?;
+
+        // only the boot ROM answers vendor requests
+        if card.firmware_running || request != FIRMWARE_LOAD {
+            return Err(rusb::Error::Pipe);
+        }
+
+        Ok(card.fx2_read(value, buf))
+    }
+
+    fn write_control(
+        &self,
+        _request_type: u8,
+        request: u8,
+        value: u16,
+        _index: u16,
+        buf: &[u8],
+        _timeout: Duration,
+    ) -> rusb::Result<usize> {
+        let mut card = self.card()?;
+
+        if card.firmware_running || request != FIRMWARE_LOAD {
+            return Err(rusb::Error::Pipe);
+        }
+
+        card.fx2_write(value, buf);
+
+        Ok(buf.len())
+    }
+
+    fn read_bulk(&self, endpoint: u8, buf: &mut [u8], timeout: Duration) -> rusb::Result<usize> {
+        let mut card = self.card()?;
+
+        if !card.firmware_running || endpoint != RESPONSE_ENDPOINT {
+            return Err(rusb::Error::Pipe);
+        }
+
+        let response = match card.responses.pop_front() {
+            Some(response) => response,
+            None if card.fpga == Fpga::Empty => FPGA_EMPTY_RESPONSE.to_vec(),
+            None => {
+                drop(card);
+                thread::sleep(timeout.min(IDLE_WAIT));
+                return Err(rusb::Error::Timeout);
+            }
+        };
+
+        let len = response.len().min(buf.len());
+        buf[..len].copy_from_slice(&response[..len]);
+
+        Ok(len)
+    }
+
+    fn write_bulk(&self, endpoint: u8, buf: &[u8], _timeout: Duration) -> rusb::Result<usize> {
+        let mut card = self.card()?;
+
+        if !card.firmware_running || endpoint != COMMAND_ENDPOINT {
+            return Err(rusb::Error::Pipe);
+        }
+
+        for command in FpgaCommand::decode(buf) {
+            card.command(command);
+        }
+
+        Ok(buf.len())
+    }
+
+    fn stream<'a>(
+        &'a self,
+        endpoint: u8,
+        config: TransferConfig,
+        handler: StreamHandler<'a>,
+        events: SyncSender<CaptureEvent>,
+    ) -> Result<Box<dyn BulkStream + 'a>, Error> {
+        if endpoint != DATA_ENDPOINT {
+            return Err(Error::TransferFailed(rusb::Error::Pipe));
+        }
+
+        Ok(Box::new(SimStream {
+            handle: self,
+            config,
+            pending: Vec::new(),
+            frame: None,
+            handler,
+            events,
+        }))
+    }
+}
+
+struct SimStream<'a> {
+    handle: &'a SimHandle,
+    config: TransferConfig,
+    // generated but not yet delivered
+    pending: Vec<u8>,
+    frame: Option<(ColorDepth, Vec<u8>)>,
+    handler: StreamHandler<'a>,
+    events: SyncSender<CaptureEvent>,
+}
+
+impl<'a> SimStream<'a> {
+    fn frame(&mut self, color_depth: ColorDepth) -> &[u8] {
+        if self.frame.as_ref().map(|(depth, _)| *depth) != Some(color_depth) {
+            self.frame = Some((color_depth, frame(color_depth)));
+        }
+
+        &self.frame.as_ref().unwrap().1
+    }
+}
+
+impl<'a> BulkStream for SimStream<'a> {
+    fn poll(&mut self, timeout: Duration) -> Result<(), Error> {
+        let handle = self.handle;
+        let mut card = match handle.card() {
+            Ok(card) => card,
+            Err(_) => {
+                let _ = self.events.try_send(CaptureEvent::Disconnected);
+                return Err(Error::Disconnected);
+            }
+        };
+
+        let color_depth = match card.fpga {
+            Fpga::Configured(color_depth) if card.streaming => color_depth,
+            _ => {
+                drop(card);
+                self.pending.clear();
+                thread::sleep(timeout.min(IDLE_WAIT));
+                return Ok(());
+            }
+        };
+
+        // every queued transfer completes, the handler runs without the card locked
+        let mut transfers = Vec::with_capacity(self.config.queue_depth);
+        for _ in 0..self.config.queue_depth.max(1) {
+            while self.pending.len() < self.config.transfer_size {
+                let frame = self.frame(color_depth).to_vec();
+                self.pending.extend_from_slice(&frame);
+                card.frames_sent += 1;
+            }
+            let transfer: Vec<u8> = self.pending.drain(..self.config.transfer_size).collect();
+
+            if card.dropped_transfers > 0 {
+                card.dropped_transfers -= 1;
+            } else if card.stall {
+                card.stall = false;
+                let _ = self.events.try_send(CaptureEvent::Stall);
+            } else {
+                transfers.push(transfer);
+            }
+        }
+        drop(card);
+
+        for transfer in transfers {
+            (self.handler)(&transfer);
+        }
+
+        thread::sleep(STREAM_WAIT);
+
+        Ok(())
+    }
+}
+
+// one frame as the card sends it, see the layout notes at the top
+fn frame(color_depth: ColorDepth) -> Vec<u8> {
+    let (upper, lower) = match color_depth {
+        ColorDepth::Rgb565 => (rgb565(SIM_UPPER_COLOR), rgb565(SIM_LOWER_COLOR)),
+        ColorDepth::Rgb888 => (SIM_UPPER_COLOR[..3].to_vec(), SIM_LOWER_COLOR[..3].to_vec()),
+    };
+
+    let pixel_bytes = LINE_PIXELS * color_depth.bytes_per_pixel();
+    let mut frame = Vec::with_capacity(FRAME_LINES * (AUDIO_BYTES + pixel_bytes));
+
+    for line in 0..FRAME_LINES {
+        let pixels = match line {
+            line if line < AUDIO_LINES => vec![0; pixel_bytes],
+            line if line <= PIXELS_FIRST_LINE => lower.repeat(LINE_PIXELS),
+            _ => upper.repeat(LINE_PIXELS),
+        };
+
+        if line < PIXELS_FIRST_LINE {
+            frame.extend_from_slice(&line_header(line));
+            frame.extend_from_slice(&pixels);
+        } else {
+            frame.extend_from_slice(&pixels);
+            frame.extend_from_slice(&line_header(line));
+        }
+    }
+
+    frame
+}
+
+// 33CC, the line number with 0xc000 set (plain 0000 starts a frame), then two sample counters
+fn line_header(line: usize) -> [u8; AUDIO_BYTES] {
+    let mut header = [0; AUDIO_BYTES];
+
+    header[0..2].copy_from_slice(&[0x33, 0xcc]);
+    if line > 0 {
+        header[2..4].copy_from_slice(&(0xc000 | line as u16).to_le_bytes());
+    }
+    header[4..6].copy_from_slice(&(line as u16 * 2).to_le_bytes());
+    header[10..12].copy_from_slice(&(line as u16 * 2 + 1).to_le_bytes());
+
+    header
+}
+
+fn rgb565(color: [u8; 4]) -> Vec<u8> {
+    let [r, g, b, _] = color.map(u16::from);
+    let pixel = ((r >> 3) << 11) | ((g >> 2) << 5) | (b >> 3);
+
+    pixel.to_le_bytes().to_vec()
+}
@@ -1,13 +1,479 @@
+use std::path::Path;
+use std::sync::mpsc::{Receiver, SyncSender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::{thread, time};
 
+use bytes::BytesMut;
 
-use rusb::Context;
+use super::bus::{OpenDevice, UsbBus, UsbHandle, UsbSelector};
+use super::devices::DeviceProfile;
+use super::katsukitty::parse::rgb888_to_rgba;
+use super::transfer::TransferConfig;
+use super::transport::Transport;
+use super::worker::{self, CaptureDriver};
+use super::{Capture, CaptureBackend, ColorDepth, ConnectConfig, DataCallback, HandleSlot};
+use crate::session::{CaptureEvent, CaptureSession, Command};
+use crate::capabilities;
+use crate::{AudioFormat, Capabilities, DeviceInfo, Error};
 
+// Experimental, nothing here has been checked against a real card or a capture of the vendor
+// software, so it is only built with the `experimental-loopy-n3dsxl` feature.
+//
+// The card is an FTDI FT601 USB 3 FIFO bridge in front of the capture FPGA. The device table
+// gives its ids, the data endpoint and the interfaces to claim.
+//
+// Every read from an FT601 IN pipe is preceded by a session request on endpoint 0x01. The
+// endpoint, request layout and read opcode are our understanding of how FTDI's D3XX driver
+// talks to the FT601 itself, they have not been checked against traffic from this card.
+const SESSION_OUT: u8 = 0x01;
+const FT_READ_REQUEST: u8 = 0x01;
 
-pub fn connect<T: UsbContext>(context: &mut T) -> Result<DeviceHandle<T>, SimpleError> {
+// Unconfirmed: the frame layout is a guess from the screen sizes. Top + Bottom width
+// 400 + 320 = 720
+// height 240, assumed to be sent a column at a time, bottom screen first
+// RGB888
+// 3 bytes per pixel
+const VIDEO_SIZE: usize = 720 * 240 * 3;
+const LOWER_SIZE: usize = 320 * 240 * 3;
 
-    //let vid = 0x0403;
-    //let pid = 0x601e;
+// Unconfirmed: assumed to be 16 bit stereo samples after the video, one frame at 32728Hz
+// needs about 1100 of them
+const AUDIO_SIZE: usize = 0x1000;
+const AUDIO_FORMAT: AudioFormat = AudioFormat {
+    sample_rate: 32728,
+    channels: 2,
+};
 
-   // "N3DSXL.2"
+const FRAME_BUFFER_SIZE: usize = VIDEO_SIZE + AUDIO_SIZE;
 
-}
\ No newline at end of file
+pub struct LoopyN3dsxl {
+    device_handle: HandleSlot,
+    bus: Option<Arc<dyn UsbBus>>,
+    connect_config: ConnectConfig,
+    profile: DeviceProfile,
+}
+
+impl LoopyN3dsxl {
+    pub fn new(profile: DeviceProfile) -> Self {
+        Self {
+            device_handle: Arc::new(Mutex::new(None)),
+            bus: None,
+            connect_config: ConnectConfig::default(),
+            profile,
+        }
+    }
+}
+
+impl CaptureBackend for LoopyN3dsxl {
+    fn name(&self) -> &'static str {
+        "Loopy N3DSXL"
+    }
+
+    fn profile(&self) -> &DeviceProfile {
+        &self.profile
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            upper_screen: capabilities::UPPER_3DS,
+            lower_screen: capabilities::LOWER_3DS,
+            // the FPGA always sends RGB888
+            color_depths: &[ColorDepth::Rgb888],
+            audio: Some(AUDIO_FORMAT),
+            stereoscopic: false,
+            frame_rate: capabilities::FRAME_RATE_3DS,
+        }
+    }
+
+    fn connect(&mut self, bus: &Arc<dyn UsbBus>, config: &ConnectConfig) -> Result<DeviceInfo, Error> {
+        let (handle, info) = <Self as Capture>::connect(bus.as_ref(), config, &self.profile)?;
+
+        *self.device_handle.lock().unwrap() = Some(handle);
+        self.bus = Some(bus.clone());
+        self.connect_config = config.clone();
+
+        Ok(info)
+    }
+
+    fn start(
+        &self,
+        data_callback: DataCallback,
+        _transfer_config: TransferConfig,
+        auto_recover: bool,
+        record_to: Option<&Path>,
+    ) -> Result<CaptureSession, Error> {
+        if record_to.is_some() {
+            return Err(Error::Unsupported("recording"));
+        }
+
+        let bus = self.bus.clone().ok_or(Error::NotConnected)?;
+
+        start_capture(
+            self.device_handle.clone(),
+            bus,
+            data_callback,
+            self.connect_config.clone(),
+            self.profile.clone(),
+            auto_recover,
+        )
+    }
+
+    // nothing is streamed unless a session asks for it, an idle card has nothing to stop
+    fn stop(&self) -> Result<(), Error> {
+        match *self.device_handle.lock().unwrap() {
+            Some(_) => Ok(()),
+            None => Err(Error::NotConnected),
+        }
+    }
+}
+
+impl Capture for LoopyN3dsxl {
+    fn connect(
+        bus: &dyn UsbBus,
+        _config: &ConnectConfig,
+        profile: &DeviceProfile,
+    ) -> Result<(UsbHandle, DeviceInfo), Error> {
+        let (vid, pid) = (profile.vendor_id, profile.product_id);
+
+        // other FT601 boards share the VID/PID, only talk to ones that say they are a capture card
+        let OpenDevice {
+            handle,
+            description,
+        } = bus.open(UsbSelector::id(vid, pid))?.ok_or(Error::DeviceNotFound)?;
+
+        let product = description.product.clone().unwrap_or_default();
+        if let Some(expected) = &profile.product {
+            if !product.starts_with(expected.as_str()) {
+                log::debug!("Ignoring FT601 device {:?}", product);
+                return Err(Error::DeviceNotFound);
+            }
+        }
+        log::info!("Opened {:04x}:{:04x} {}", vid, pid, product);
+
+        for interface in &profile.interfaces {
+            handle.claim_interface(*interface).map_err(Error::from_open)?;
+        }
+
+        let info = DeviceInfo::new(&description, "Loopy N3DSXL", Vec::new());
+        log::info!("Connected to {}", info);
+
+        Ok((handle, info))
+    }
+}
+
+/// Builds the 20 byte FT601 session request that precedes every read from an IN pipe.
+///
+/// A little endian request counter, the pipe, the request type, two unused bytes, a little
+/// endian length and eight unused bytes.
+fn read_request(index: u32, pipe: u8, length: u32) -> [u8; 20] {
+    let mut request = [0; 20];
+
+    request[0..4].copy_from_slice(&index.to_le_bytes());
+    request[4] = pipe;
+    request[5] = FT_READ_REQUEST;
+    request[8..12].copy_from_slice(&length.to_le_bytes());
+
+    request
+}
+
+/// Reads one frame worth of video and audio, returning how many bytes arrived.
+fn read_frame<H: Transport + ?Sized>(
+    handle: &H,
+    data_endpoint: u8,
+    request_index: &mut u32,
+    buf: &mut [u8],
+) -> Result<usize, Error> {
+    let timeout = time::Duration::from_millis(100);
+
+    let request = read_request(*request_index, data_endpoint, buf.len() as u32);
+    *request_index = request_index.wrapping_add(1);
+
+    handle
+        .write_bulk(SESSION_OUT, &request, timeout)
+        .map_err(Error::from_transfer)?;
+
+    match handle.read_bulk(data_endpoint, buf, timeout) {
+        Ok(len) => Ok(len),
+        Err(rusb::Error::Timeout) => Ok(0),
+        Err(err) => Err(Error::from_transfer(err)),
+    }
+}
+
+pub fn start_capture<F>(
+    device_handle: HandleSlot,
+    bus: Arc<dyn UsbBus>,
+    data_callback: Arc<Mutex<F>>,
+    connect_config: ConnectConfig,
+    profile: DeviceProfile,
+    auto_recover: bool,
+) -> Result<CaptureSession, Error>
+where
+    F: ?Sized + FnMut(&[i16], BytesMut, BytesMut) + Send + 'static,
+{
+    let reader = FrameReader { data_callback };
+
+    worker::spawn_capture(
+        device_handle,
+        bus,
+        connect_config,
+        profile,
+        auto_recover,
+        reader,
+    )
+}
+
+/// Requests one frame after another from the FT601 while a session is capturing.
+struct FrameReader<F: ?Sized> {
+    data_callback: Arc<Mutex<F>>,
+}
+
+impl<F> CaptureDriver for FrameReader<F>
+where
+    F: ?Sized + FnMut(&[i16], BytesMut, BytesMut) + Send + 'static,
+{
+    type Card = LoopyN3dsxl;
+
+    fn read_frames(
+        &mut self,
+        handle: &dyn Transport,
+        profile: &DeviceProfile,
+        commands: &Receiver<Command>,
+        events: &SyncSender<CaptureEvent>,
+        paused: &mut bool,
+    ) -> Result<(), Error> {
+        read_frames(
+            handle,
+            profile,
+            &self.data_callback,
+            commands,
+            events,
+            paused,
+        )
+    }
+}
+
+fn read_frames<H: Transport + ?Sized, F>(
+    handle: &H,
+    profile: &DeviceProfile,
+    data_callback: &Arc<Mutex<F>>,
+    commands: &Receiver<Command>,
+    events: &SyncSender<CaptureEvent>,
+    paused: &mut bool,
+) -> Result<(), Error>
+where
+    F: ?Sized + FnMut(&[i16], BytesMut, BytesMut) + Send + 'static,
+{
+    log::debug!("Starting Loopy N3DSXL capture");
+
+    let idle_delay = time::Duration::from_millis(100);
+
+    let mut buf = vec![0; FRAME_BUFFER_SIZE];
+    let mut request_index = 0;
+
+    loop {
+        match commands.try_recv() {
+            Ok(Command::Pause) => *paused = true,
+            Ok(Command::Resume) => *paused = false,
+            Ok(Command::Stop) | Err(TryRecvError::Disconnected) => break,
+            Err(TryRecvError::Empty) => {}
+        }
+
+        // nothing is streamed unless we ask for it, so pausing just means not asking
+        if *paused {
+            thread::sleep(idle_delay);
+            continue;
+        }
+
+        let len = read_frame(handle, profile.endpoints.data, &mut request_index, &mut buf)?;
+
+        if len < VIDEO_SIZE {
+            // the console is off or asleep
+            let _ = events.try_send(CaptureEvent::Timeout);
+            continue;
+        }
+
+        let (video, audio) = buf[..len].split_at(VIDEO_SIZE);
+
+        let audio: Vec<i16> = audio
+            .chunks_exact(2)
+            .map(|sample| i16::from_le_bytes([sample[0], sample[1]]))
+            .collect();
+
+        (data_callback.lock().unwrap())(
+            &audio,
+            rgb888_to_rgba(&video[LOWER_SIZE..]),
+            rgb888_to_rgba(&video[..LOWER_SIZE]),
+        );
+    }
+
+    log::debug!("Stopping capture");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::bus::{UsbDescription, UsbDevice};
+    use crate::capture::devices::DeviceTable;
+    use crate::capture::transport::{Exchange, MockTransport};
+    use std::sync::mpsc;
+
+    fn profile() -> DeviceProfile {
+        DeviceTable::builtin()
+            .find(0x0403, 0x601e, Some("N3DSXL"))
+            .unwrap()
+            .clone()
+    }
+
+    fn request(index: u32, pipe: u8, length: u32) -> Exchange {
+        Exchange::BulkOut {
+            endpoint: SESSION_OUT,
+            data: read_request(index, pipe, length).to_vec(),
+        }
+    }
+
+    // hands out one FT601 with this product string
+    struct OneDevice(Mutex<Option<OpenDevice>>);
+
+    impl OneDevice {
+        fn new(product: &str) -> Self {
+            let profile = profile();
+            Self(Mutex::new(Some(OpenDevice {
+                handle: Box::new(MockTransport::default()),
+                description: UsbDescription {
+                    vendor_id: profile.vendor_id,
+                    product_id: profile.product_id,
+                    product: Some(product.to_string()),
+                    ..UsbDescription::default()
+                },
+            })))
+        }
+    }
+
+    impl UsbBus for OneDevice {
+        fn devices(&self) -> Result<Vec<UsbDevice>, Error> {
+            Ok(Vec::new())
+        }
+
+        fn open(&self, _selector: UsbSelector) -> Result<Option<OpenDevice>, Error> {
+            Ok(self.0.lock().unwrap().take())
+        }
+    }
+
+    #[test]
+    fn read_request_layout() {
+        assert_eq!(
+            read_request(0x01020304, 0x82, 0x0a0b0c0d),
+            [
+                0x04, 0x03, 0x02, 0x01, 0x82, 0x01, 0x00, 0x00, 0x0d, 0x0c, 0x0b, 0x0a, 0x00, 0x00,
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            ]
+        );
+    }
+
+    #[test]
+    fn every_read_is_requested_first() {
+        let data_endpoint = profile().endpoints.data;
+        let handle = MockTransport::new([
+            request(0, data_endpoint, 4),
+            Exchange::BulkIn {
+                endpoint: data_endpoint,
+                data: vec![1, 2, 3, 4],
+            },
+            // nothing sent back in time
+            request(1, data_endpoint, 4),
+        ]);
+
+        let mut request_index = 0;
+        let mut buf = [0; 4];
+
+        assert_eq!(
+            read_frame(&handle, data_endpoint, &mut request_index, &mut buf).unwrap(),
+            4
+        );
+        assert_eq!(buf, [1, 2, 3, 4]);
+        assert_eq!(
+            read_frame(&handle, data_endpoint, &mut request_index, &mut buf).unwrap(),
+            0
+        );
+        assert_eq!(request_index, 2);
+        handle.assert_done();
+    }
+
+    #[test]
+    fn other_ft601_boards_are_ignored() {
+        let bus = OneDevice::new("FTDI SuperSpeed-FIFO Bridge");
+        let result = <LoopyN3dsxl as Capture>::connect(&bus, &ConnectConfig::default(), &profile());
+        assert!(matches!(result, Err(Error::DeviceNotFound)));
+
+        let bus = OneDevice::new("N3DSXL capture");
+        let (_handle, info) =
+            <LoopyN3dsxl as Capture>::connect(&bus, &ConnectConfig::default(), &profile()).unwrap();
+        assert_eq!(info.model, "N3DSXL capture");
+    }
+
+    #[test]
+    fn frames_split_into_screens_and_audio() {
+        let profile = profile();
+        let data_endpoint = profile.endpoints.data;
+        let length = FRAME_BUFFER_SIZE as u32;
+
+        let audio: Vec<u8> = (1..=AUDIO_SIZE as u16 / 2)
+            .flat_map(|sample| sample.to_le_bytes())
+            .collect();
+        let frame = [
+            [1, 2, 3].repeat(LOWER_SIZE / 3),
+            [4, 5, 6].repeat((VIDEO_SIZE - LOWER_SIZE) / 3),
+            audio,
+        ]
+        .concat();
+
+        let handle = MockTransport::new([
+            // console off: a timeout is reported
+            request(0, data_endpoint, length),
+            request(1, data_endpoint, length),
+            Exchange::BulkIn {
+                endpoint: data_endpoint,
+                data: frame,
+            },
+        ]);
+
+        let (commands_tx, commands) = mpsc::channel();
+        let (events_tx, events) = mpsc::sync_channel(8);
+        let (frames_tx, frames) = mpsc::channel();
+
+        let data_callback = Arc::new(Mutex::new(
+            move |audio: &[i16], upper: BytesMut, lower: BytesMut| {
+                frames_tx.send((audio.to_vec(), upper, lower)).unwrap();
+                commands_tx.send(Command::Stop).unwrap();
+            },
+        ));
+
+        let mut paused = false;
+        read_frames(
+            &handle,
+            &profile,
+            &data_callback,
+            &commands,
+            &events_tx,
+            &mut paused,
+        )
+        .unwrap();
+        handle.assert_done();
+
+        assert_eq!(
+            events.try_iter().collect::<Vec<_>>(),
+            [CaptureEvent::Timeout]
+        );
+
+        let (audio, upper, lower) = frames.try_recv().unwrap();
+        assert_eq!(audio.len(), AUDIO_SIZE / 2);
+        assert_eq!(audio[..3], [1, 2, 3]);
+        assert_eq!(upper.len(), 400 * 240 * 4);
+        assert_eq!(lower.len(), 320 * 240 * 4);
+        assert_eq!(upper[..4], [4, 5, 6, 0]);
+        assert_eq!(lower[..4], [1, 2, 3, 0]);
+        assert!(frames.try_recv().is_err());
+    }
+}
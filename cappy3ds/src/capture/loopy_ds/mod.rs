@@ -1,22 +1,646 @@
-USBDS_VID               = 0x16D0,
-USBDS_PID               = 0x0647,
-
-    //device vendor requests:
-    CMDIN_STATUS            = 0x31,
-        // Returns device status:
-        // struct {
-        //     u32 framecount,              //free running frame counter
-        //     u8 lcd_on,
-        //     u8 capture_in_progress,
-        // }
-    CMDIN_FRAMEINFO         = 0x30,
-        // Returns record of last captured frame:
-        // struct {
-        //     u8 bitmap[48],     //bitmap of lines sent (1 bit per half-line)
-        //     u32 frame,         //frame number
-        //     u8 valid,          //0 if capture timed out (LCD is inactive)
-        // }
-    CMDOUT_CAPTURE_START    = 0x30,
-        // capture new frame
-    CMDOUT_CAPTURE_STOP     = 0x31,
-        // stop capture in progress and reset frame counter to 0
\ No newline at end of file
+use std::path::Path;
+use std::sync::mpsc::{Receiver, SyncSender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::{thread, time};
+
+use bytes::BytesMut;
+use rusb::{Direction, Recipient, RequestType};
+
+use super::bus::{OpenDevice, UsbBus, UsbHandle, UsbSelector};
+use super::devices::DeviceProfile;
+use super::transfer::TransferConfig;
+use super::transport::Transport;
+use super::worker::{self, CaptureDriver};
+use super::{Capture, CaptureBackend, ColorDepth, ConnectConfig, DataCallback, HandleSlot};
+use crate::session::{CaptureEvent, CaptureSession, Command};
+use crate::{Capabilities, DeviceInfo, Error, ScreenGeometry};
+
+// device vendor requests:
+
+// Returns device status:
+// struct {
+//     u32 framecount,              //free running frame counter
+//     u8 lcd_on,
+//     u8 capture_in_progress,
+// }
+const CMDIN_STATUS: u8 = 0x31;
+// Returns record of last captured frame:
+// struct {
+//     u8 bitmap[48],     //bitmap of lines sent (1 bit per half-line)
+//     u32 frame,         //frame number
+//     u8 valid,          //0 if capture timed out (LCD is inactive)
+// }
+const CMDIN_FRAMEINFO: u8 = 0x30;
+// capture new frame
+const CMDOUT_CAPTURE_START: u8 = 0x30;
+// stop capture in progress and reset frame counter to 0
+const CMDOUT_CAPTURE_STOP: u8 = 0x31;
+
+const WIDTH: usize = 256;
+const HEIGHT: usize = 192;
+
+const SCREEN: ScreenGeometry = ScreenGeometry {
+    width: WIDTH as u32,
+    height: HEIGHT as u32,
+    rotated: false,
+};
+
+// Each line is sent as two half-lines. A half-line carries 128 pixels of both screens,
+// alternating top and bottom, as 16 bit RGB565.
+const HALF_LINES: usize = HEIGHT * 2;
+const HALF_LINE_PIXELS: usize = WIDTH / 2;
+const HALF_LINE_BYTES: usize = HALF_LINE_PIXELS * 2 * 2;
+const FRAME_SIZE: usize = HALF_LINES * HALF_LINE_BYTES;
+
+const FRAMEINFO_SIZE: usize = 53;
+const STATUS_SIZE: usize = 6;
+
+/// What the card reports about the console between frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Status {
+    pub frame_count: u32,
+    pub lcd_on: bool,
+    pub capture_in_progress: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FrameInfo {
+    bitmap: [u8; 48],
+    frame: u32,
+    valid: bool,
+}
+
+impl FrameInfo {
+    fn half_line_valid(&self, half_line: usize) -> bool {
+        self.bitmap[half_line / 8] & (1 << (half_line % 8)) != 0
+    }
+}
+
+pub struct LoopyDs {
+    device_handle: HandleSlot,
+    bus: Option<Arc<dyn UsbBus>>,
+    connect_config: ConnectConfig,
+    profile: DeviceProfile,
+}
+
+impl LoopyDs {
+    pub fn new(profile: DeviceProfile) -> Self {
+        Self {
+            device_handle: Arc::new(Mutex::new(None)),
+            bus: None,
+            connect_config: ConnectConfig::default(),
+            profile,
+        }
+    }
+}
+
+impl CaptureBackend for LoopyDs {
+    fn name(&self) -> &'static str {
+        "Loopy DS"
+    }
+
+    fn profile(&self) -> &DeviceProfile {
+        &self.profile
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            upper_screen: SCREEN,
+            lower_screen: SCREEN,
+            color_depths: &[ColorDepth::Rgb565],
+            audio: None,
+            stereoscopic: false,
+            frame_rate: 59.83,
+        }
+    }
+
+    fn connect(&mut self, bus: &Arc<dyn UsbBus>, config: &ConnectConfig) -> Result<DeviceInfo, Error> {
+        let (handle, info) = <Self as Capture>::connect(bus.as_ref(), config, &self.profile)?;
+
+        *self.device_handle.lock().unwrap() = Some(handle);
+        self.bus = Some(bus.clone());
+        self.connect_config = config.clone();
+
+        Ok(info)
+    }
+
+    fn start(
+        &self,
+        data_callback: DataCallback,
+        _transfer_config: TransferConfig,
+        auto_recover: bool,
+        record_to: Option<&Path>,
+    ) -> Result<CaptureSession, Error> {
+        if record_to.is_some() {
+            return Err(Error::Unsupported("recording"));
+        }
+
+        let bus = self.bus.clone().ok_or(Error::NotConnected)?;
+
+        start_capture(
+            self.device_handle.clone(),
+            bus,
+            data_callback,
+            self.connect_config.clone(),
+            self.profile.clone(),
+            auto_recover,
+        )
+    }
+
+    fn stop(&self) -> Result<(), Error> {
+        let handle = self.device_handle.lock().unwrap();
+        vendor_out(handle.as_deref().ok_or(Error::NotConnected)?, CMDOUT_CAPTURE_STOP)
+    }
+}
+
+impl Capture for LoopyDs {
+    fn connect(
+        bus: &dyn UsbBus,
+        _config: &ConnectConfig,
+        profile: &DeviceProfile,
+    ) -> Result<(UsbHandle, DeviceInfo), Error> {
+        let (vid, pid) = (profile.vendor_id, profile.product_id);
+
+        // no firmware or FPGA to set up, the card is ready as soon as it enumerates
+        let OpenDevice {
+            handle,
+            description,
+        } = bus.open(UsbSelector::id(vid, pid))?.ok_or(Error::DeviceNotFound)?;
+        log::info!("Opened {:04x}:{:04x}", vid, pid);
+
+        for interface in &profile.interfaces {
+            handle.claim_interface(*interface).map_err(Error::from_open)?;
+        }
+
+        let status = read_status(&*handle)?;
+        log::debug!("Loopy DS status {:?}", status);
+
+        let info = DeviceInfo::new(&description, "Loopy DS", Vec::new());
+        log::info!("Connected to {}", info);
+
+        Ok((handle, info))
+    }
+}
+
+fn vendor_in<H: Transport + ?Sized>(
+    handle: &H,
+    request: u8,
+    buf: &mut [u8],
+) -> Result<usize, Error> {
+    let timeout = time::Duration::from_secs(1);
+    let request_type = rusb::request_type(Direction::In, RequestType::Vendor, Recipient::Device);
+
+    handle
+        .read_control(request_type, request, 0, 0, buf, timeout)
+        .map_err(Error::from_transfer)
+}
+
+fn vendor_out<H: Transport + ?Sized>(handle: &H, request: u8) -> Result<(), Error> {
+    let timeout = time::Duration::from_secs(1);
+    let request_type = rusb::request_type(Direction::Out, RequestType::Vendor, Recipient::Device);
+
+    handle
+        .write_control(request_type, request, 0, 0, &[], timeout)
+        .map_err(Error::from_transfer)?;
+
+    Ok(())
+}
+
+pub fn read_status<H: Transport + ?Sized>(handle: &H) -> Result<Status, Error> {
+    let mut buf = [0; STATUS_SIZE];
+    if vendor_in(handle, CMDIN_STATUS, &mut buf)? < STATUS_SIZE {
+        return Err(Error::TransferFailed(rusb::Error::Io));
+    }
+
+    Ok(Status {
+        frame_count: u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]),
+        lcd_on: buf[4] != 0,
+        capture_in_progress: buf[5] != 0,
+    })
+}
+
+fn read_frame_info<H: Transport + ?Sized>(handle: &H) -> Result<FrameInfo, Error> {
+    let mut buf = [0; FRAMEINFO_SIZE];
+    if vendor_in(handle, CMDIN_FRAMEINFO, &mut buf)? < FRAMEINFO_SIZE {
+        return Err(Error::TransferFailed(rusb::Error::Io));
+    }
+
+    let mut bitmap = [0; 48];
+    bitmap.copy_from_slice(&buf[..48]);
+
+    Ok(FrameInfo {
+        bitmap,
+        frame: u32::from_le_bytes([buf[48], buf[49], buf[50], buf[51]]),
+        valid: buf[52] != 0,
+    })
+}
+
+/// Asks for one frame and reads it, returning the frame info and how many bytes arrived.
+fn grab_frame<H: Transport + ?Sized>(
+    handle: &H,
+    data_endpoint: u8,
+    buf: &mut [u8],
+) -> Result<(FrameInfo, usize), Error> {
+    let timeout = time::Duration::from_millis(100);
+
+    vendor_out(handle, CMDOUT_CAPTURE_START)?;
+
+    // only the half-lines that changed are sent, a short read ends the frame
+    let mut received = 0;
+    while received < buf.len() {
+        match handle.read_bulk(data_endpoint, &mut buf[received..], timeout) {
+            Ok(0) => break,
+            Ok(len) => received += len,
+            Err(rusb::Error::Timeout) => break,
+            Err(err) => return Err(Error::from_transfer(err)),
+        }
+    }
+
+    Ok((read_frame_info(handle)?, received))
+}
+
+/// The last complete picture of both screens, updated half-line by half-line.
+struct Screens {
+    upper: Vec<u8>,
+    lower: Vec<u8>,
+}
+
+impl Screens {
+    fn new() -> Self {
+        Self {
+            upper: vec![0; WIDTH * HEIGHT * 4],
+            lower: vec![0; WIDTH * HEIGHT * 4],
+        }
+    }
+
+    /// Copies the half-lines flagged in `info` out of `data`, which holds them back to back.
+    fn update(&mut self, info: &FrameInfo, data: &[u8]) {
+        let mut half_lines = data.chunks_exact(HALF_LINE_BYTES);
+
+        for half_line in (0..HALF_LINES).filter(|half_line| info.half_line_valid(*half_line)) {
+            let Some(src) = half_lines.next() else {
+                break;
+            };
+
+            let y = half_line / 2;
+            let x = (half_line % 2) * HALF_LINE_PIXELS;
+
+            for (i, pixels) in src.chunks_exact(4).enumerate() {
+                let offset = ((y * WIDTH) + x + i) * 4;
+                rgb565_to_rgba(&pixels[0..2], &mut self.upper[offset..offset + 4]);
+                rgb565_to_rgba(&pixels[2..4], &mut self.lower[offset..offset + 4]);
+            }
+        }
+    }
+}
+
+fn rgb565_to_rgba(src: &[u8], dest: &mut [u8]) {
+    let c = u16::from_le_bytes([src[0], src[1]]);
+
+    dest[0] = (((c & 0xF800) >> 11) << 3) as u8;
+    dest[1] = (((c & 0x7E0) >> 5) << 2) as u8;
+    dest[2] = ((c & 0x1F) << 3) as u8;
+    dest[3] = 0;
+}
+
+pub fn start_capture<F>(
+    device_handle: HandleSlot,
+    bus: Arc<dyn UsbBus>,
+    data_callback: Arc<Mutex<F>>,
+    connect_config: ConnectConfig,
+    profile: DeviceProfile,
+    auto_recover: bool,
+) -> Result<CaptureSession, Error>
+where
+    F: ?Sized + FnMut(&[i16], BytesMut, BytesMut) + Send + 'static,
+{
+    let reader = FrameReader { data_callback };
+
+    worker::spawn_capture(
+        device_handle,
+        bus,
+        connect_config,
+        profile,
+        auto_recover,
+        reader,
+    )
+}
+
+/// Asks a Loopy DS for one frame after another while a session is capturing.
+struct FrameReader<F: ?Sized> {
+    data_callback: Arc<Mutex<F>>,
+}
+
+impl<F> CaptureDriver for FrameReader<F>
+where
+    F: ?Sized + FnMut(&[i16], BytesMut, BytesMut) + Send + 'static,
+{
+    type Card = LoopyDs;
+
+    fn read_frames(
+        &mut self,
+        handle: &dyn Transport,
+        profile: &DeviceProfile,
+        commands: &Receiver<Command>,
+        events: &SyncSender<CaptureEvent>,
+        paused: &mut bool,
+    ) -> Result<(), Error> {
+        read_frames(
+            handle,
+            profile,
+            &self.data_callback,
+            commands,
+            events,
+            paused,
+        )
+    }
+
+    fn stop(&self, handle: &dyn Transport, _profile: &DeviceProfile) -> Result<(), Error> {
+        vendor_out(handle, CMDOUT_CAPTURE_STOP)
+    }
+
+    // the status read during connect fails while the card is still coming up
+    fn is_not_ready(&self, err: &Error) -> bool {
+        matches!(err, Error::TransferFailed(_))
+    }
+}
+
+fn read_frames<H: Transport + ?Sized, F>(
+    handle: &H,
+    profile: &DeviceProfile,
+    data_callback: &Arc<Mutex<F>>,
+    commands: &Receiver<Command>,
+    events: &SyncSender<CaptureEvent>,
+    paused: &mut bool,
+) -> Result<(), Error>
+where
+    F: ?Sized + FnMut(&[i16], BytesMut, BytesMut) + Send + 'static,
+{
+    log::debug!("Starting Loopy DS capture");
+
+    // how long to wait before asking again while the console screens are off
+    let idle_delay = time::Duration::from_millis(100);
+
+    let mut buf = vec![0; FRAME_SIZE];
+    let mut screens = Screens::new();
+    let mut last_frame = None;
+
+    loop {
+        match commands.try_recv() {
+            Ok(Command::Pause) => {
+                vendor_out(handle, CMDOUT_CAPTURE_STOP)?;
+                *paused = true;
+            }
+            Ok(Command::Resume) => *paused = false,
+            Ok(Command::Stop) | Err(TryRecvError::Disconnected) => break,
+            Err(TryRecvError::Empty) => {}
+        }
+
+        if *paused {
+            thread::sleep(idle_delay);
+            continue;
+        }
+
+        let (info, received) = grab_frame(handle, profile.endpoints.data, &mut buf)?;
+
+        if !info.valid {
+            let status = read_status(handle)?;
+            if !status.lcd_on {
+                let _ = events.try_send(CaptureEvent::Timeout);
+                thread::sleep(idle_delay);
+            }
+            continue;
+        }
+
+        if last_frame == Some(info.frame) {
+            continue;
+        }
+        last_frame = Some(info.frame);
+
+        screens.update(&info, &buf[..received]);
+
+        // the DS card has no audio
+        (data_callback.lock().unwrap())(
+            &[],
+            BytesMut::from(&screens.upper[..]),
+            BytesMut::from(&screens.lower[..]),
+        );
+    }
+
+    log::debug!("Stopping capture");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::devices::DeviceTable;
+    use crate::capture::transport::{Exchange, MockTransport};
+    use std::sync::mpsc;
+
+    const VENDOR_IN: u8 = 0xc0;
+    const VENDOR_OUT: u8 = 0x40;
+
+    const RED: [u8; 2] = 0xf800u16.to_le_bytes();
+    const GREEN: [u8; 2] = 0x07e0u16.to_le_bytes();
+    const BLUE: [u8; 2] = 0x001fu16.to_le_bytes();
+
+    fn frame_info(half_lines: &[usize], frame: u32, valid: bool) -> Vec<u8> {
+        let mut info = vec![0; FRAMEINFO_SIZE];
+        for half_line in half_lines {
+            info[half_line / 8] |= 1 << (half_line % 8);
+        }
+        info[48..52].copy_from_slice(&frame.to_le_bytes());
+        info[52] = valid as u8;
+        info
+    }
+
+    fn status(lcd_on: bool) -> Vec<u8> {
+        vec![7, 0, 0, 0, lcd_on as u8, 1]
+    }
+
+    fn control_in(request: u8, response: Vec<u8>) -> Exchange {
+        Exchange::ControlIn {
+            request_type: VENDOR_IN,
+            request,
+            value: 0,
+            index: 0,
+            response,
+        }
+    }
+
+    fn control_out(request: u8) -> Exchange {
+        Exchange::ControlOut {
+            request_type: VENDOR_OUT,
+            request,
+            value: 0,
+            index: 0,
+            data: Vec::new(),
+        }
+    }
+
+    // one half-line with the same upper and lower pixel all the way along
+    fn half_line(upper: [u8; 2], lower: [u8; 2]) -> Vec<u8> {
+        [upper, lower].concat().repeat(HALF_LINE_PIXELS)
+    }
+
+    fn pixel(screen: &[u8], x: usize, y: usize) -> &[u8] {
+        let offset = (y * WIDTH + x) * 4;
+        &screen[offset..offset + 4]
+    }
+
+    fn parse_frame_info(info: Vec<u8>) -> FrameInfo {
+        read_frame_info(&MockTransport::new([control_in(CMDIN_FRAMEINFO, info)])).unwrap()
+    }
+
+    #[test]
+    fn frame_info_flags_half_lines() {
+        let info = parse_frame_info(frame_info(&[0, 9, HALF_LINES - 1], 0x01020304, true));
+
+        assert_eq!(info.frame, 0x01020304);
+        assert!(info.valid);
+        assert!(info.half_line_valid(0));
+        assert!(info.half_line_valid(9));
+        assert!(info.half_line_valid(HALF_LINES - 1));
+        assert!(!info.half_line_valid(1));
+        assert!(!info.half_line_valid(8));
+
+        assert!(!parse_frame_info(frame_info(&[], 1, false)).valid);
+    }
+
+    #[test]
+    fn short_replies_are_errors() {
+        let handle = MockTransport::new([
+            control_in(CMDIN_FRAMEINFO, vec![0; FRAMEINFO_SIZE - 1]),
+            control_in(CMDIN_STATUS, vec![0; STATUS_SIZE - 1]),
+        ]);
+
+        assert!(matches!(
+            read_frame_info(&handle),
+            Err(Error::TransferFailed(_))
+        ));
+        assert!(matches!(
+            read_status(&handle),
+            Err(Error::TransferFailed(_))
+        ));
+    }
+
+    #[test]
+    fn status_reports_the_lcd() {
+        let handle = MockTransport::new([control_in(CMDIN_STATUS, status(true))]);
+
+        assert_eq!(
+            read_status(&handle).unwrap(),
+            Status {
+                frame_count: 7,
+                lcd_on: true,
+                capture_in_progress: true,
+            }
+        );
+    }
+
+    #[test]
+    fn half_lines_land_on_both_screens() {
+        let mut screens = Screens::new();
+
+        // the left half of line 0 and the right half of line 1
+        let info = parse_frame_info(frame_info(&[0, 3], 1, true));
+        let data = [half_line(RED, BLUE), half_line(GREEN, RED)].concat();
+        screens.update(&info, &data);
+
+        assert_eq!(pixel(&screens.upper, 0, 0), [0xf8, 0, 0, 0]);
+        assert_eq!(
+            pixel(&screens.lower, HALF_LINE_PIXELS - 1, 0),
+            [0, 0, 0xf8, 0]
+        );
+        assert_eq!(pixel(&screens.upper, HALF_LINE_PIXELS, 1), [0, 0xfc, 0, 0]);
+        assert_eq!(pixel(&screens.lower, WIDTH - 1, 1), [0xf8, 0, 0, 0]);
+
+        // half-lines that were not sent stay black
+        assert_eq!(pixel(&screens.upper, HALF_LINE_PIXELS, 0), [0, 0, 0, 0]);
+        assert_eq!(pixel(&screens.upper, 0, 1), [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn unsent_half_lines_keep_the_last_picture() {
+        let mut screens = Screens::new();
+
+        let every_half_line: Vec<usize> = (0..HALF_LINES).collect();
+        let info = parse_frame_info(frame_info(&every_half_line, 1, true));
+        screens.update(&info, &half_line(RED, RED).repeat(HALF_LINES));
+
+        // the bitmap asks for more half-lines than arrived, the missing ones are left alone
+        let info = parse_frame_info(frame_info(&[2, 5], 2, true));
+        screens.update(&info, &half_line(BLUE, GREEN));
+
+        assert_eq!(pixel(&screens.upper, 0, 1), [0, 0, 0xf8, 0]);
+        assert_eq!(pixel(&screens.lower, 0, 1), [0, 0xfc, 0, 0]);
+        assert_eq!(pixel(&screens.upper, HALF_LINE_PIXELS, 2), [0xf8, 0, 0, 0]);
+        assert_eq!(
+            pixel(&screens.upper, WIDTH - 1, HEIGHT - 1),
+            [0xf8, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn frames_are_only_delivered_while_the_lcd_is_on() {
+        let profile = DeviceTable::builtin()
+            .find(0x16d0, 0x0647, None)
+            .unwrap()
+            .clone();
+        let data_endpoint = profile.endpoints.data;
+
+        let handle = MockTransport::new([
+            // console off: a timeout is reported
+            control_out(CMDOUT_CAPTURE_START),
+            control_in(CMDIN_FRAMEINFO, frame_info(&[], 0, false)),
+            control_in(CMDIN_STATUS, status(false)),
+            // screen on but nothing captured yet: quietly ask again
+            control_out(CMDOUT_CAPTURE_START),
+            control_in(CMDIN_FRAMEINFO, frame_info(&[], 0, false)),
+            control_in(CMDIN_STATUS, status(true)),
+            control_out(CMDOUT_CAPTURE_START),
+            Exchange::BulkIn {
+                endpoint: data_endpoint,
+                data: half_line(RED, BLUE),
+            },
+            control_in(CMDIN_FRAMEINFO, frame_info(&[0], 1, true)),
+        ]);
+
+        let (commands_tx, commands) = mpsc::channel();
+        let (events_tx, events) = mpsc::sync_channel(8);
+        let (frames_tx, frames) = mpsc::channel();
+
+        let data_callback = Arc::new(Mutex::new(
+            move |audio: &[i16], upper: BytesMut, lower: BytesMut| {
+                frames_tx.send((audio.len(), upper, lower)).unwrap();
+                commands_tx.send(Command::Stop).unwrap();
+            },
+        ));
+
+        let mut paused = false;
+        read_frames(
+            &handle,
+            &profile,
+            &data_callback,
+            &commands,
+            &events_tx,
+            &mut paused,
+        )
+        .unwrap();
+        handle.assert_done();
+
+        assert_eq!(
+            events.try_iter().collect::<Vec<_>>(),
+            [CaptureEvent::Timeout]
+        );
+
+        let (audio, upper, lower) = frames.try_recv().unwrap();
+        assert_eq!(audio, 0);
+        assert_eq!(upper.len(), WIDTH * HEIGHT * 4);
+        assert_eq!(pixel(&upper, 0, 0), [0xf8, 0, 0, 0]);
+        assert_eq!(pixel(&lower, 0, 0), [0, 0, 0xf8, 0]);
+        assert!(frames.try_recv().is_err());
+    }
+}
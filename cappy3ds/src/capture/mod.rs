@@ -1,36 +1,180 @@
+pub mod bus;
+pub mod devices;
+pub mod fx2;
 pub mod katsukitty;
+#[cfg(feature = "experimental-loopy-n3dsxl")]
+pub mod loopy;
+pub mod loopy_ds;
+pub mod registry;
+pub mod transfer;
+pub mod transport;
+mod worker;
 
-use simple_error::SimpleError;
-use rusb::{Device, DeviceDescriptor, DeviceHandle, UsbContext};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bytes::BytesMut;
+use serde::Deserialize;
+
+use crate::{Capabilities, CaptureSession, DeviceInfo, EepromDump, Error, FirmwareSource};
+use transfer::TransferConfig;
+
+use bus::{OpenDevice, UsbBus, UsbHandle, UsbSelector};
+use devices::{DeviceProfile, DeviceTable};
+use fx2::FirmwareImage;
+
+// how long to wait between checks while polling for a device or response
+pub(crate) const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Pixel format the card is asked to send.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorDepth {
+    /// 16 bit RGB565, what the vendor software uses
+    #[default]
+    Rgb565,
+    /// 24 bit RGB888, no banding in gradients but 50% more USB traffic
+    Rgb888,
+}
+
+impl ColorDepth {
+    pub fn bytes_per_pixel(&self) -> usize {
+        match self {
+            ColorDepth::Rgb565 => 2,
+            ColorDepth::Rgb888 => 3,
+        }
+    }
+}
+
+/// How connect sets up the card and how long it waits on each step before giving up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectConfig {
+    /// Time allowed for the FX2 to come back as the programmed device after the firmware upload
+    pub reenumeration_timeout: Duration,
+    /// Time allowed for the FPGA to answer after its configuration is sent
+    pub fpga_timeout: Duration,
+    /// Time spent listening to an already running card to find out if its FPGA is configured
+    pub detect_timeout: Duration,
+    /// Pixel format to configure the FPGA for, `None` uses the one from the device table.
+    /// Anything but RGB565 always reprograms the FPGA, there is no way to ask a running card
+    /// which format it was left in.
+    pub color_depth: Option<ColorDepth>,
+    /// Cards to look for and how to drive them
+    pub devices: DeviceTable,
+    /// Where vendor firmware and bitstreams are read from
+    pub firmware_source: FirmwareSource,
+    /// FX2 firmware to upload instead of the one from `firmware_source`, never digest checked
+    pub fx2_firmware: Option<FirmwareImage>,
+    /// Upload firmware and bitstreams from `firmware_source` even if their digest is unknown
+    pub allow_unknown_firmware: bool,
+}
+
+impl Default for ConnectConfig {
+    fn default() -> Self {
+        Self {
+            reenumeration_timeout: Duration::from_secs(10),
+            fpga_timeout: Duration::from_secs(3),
+            detect_timeout: Duration::from_millis(300),
+            color_depth: None,
+            devices: DeviceTable::default(),
+            firmware_source: FirmwareSource::default(),
+            fx2_firmware: None,
+            allow_unknown_firmware: false,
+        }
+    }
+}
+
+impl ConnectConfig {
+    /// The pixel format to use for `profile`.
+    pub(crate) fn color_depth_for(&self, profile: &DeviceProfile) -> ColorDepth {
+        self.color_depth.unwrap_or(profile.color_depth)
+    }
+}
+
+/// The frame callback as handed to a backend, shared with the session feeding it.
+pub type DataCallback = Arc<Mutex<dyn FnMut(&[i16], BytesMut, BytesMut) + Send>>;
+
+// where a connected backend keeps its device while no session is using it
+pub(crate) type HandleSlot = Arc<Mutex<Option<UsbHandle>>>;
+
+/// A capture card driver that can be picked at runtime, see `registry` for the full list.
+pub trait CaptureBackend: Send {
+    /// Human readable name of the card family
+    fn name(&self) -> &'static str;
+
+    /// The device table entry this backend was created for
+    fn profile(&self) -> &DeviceProfile;
+
+    /// Screens, formats and audio this card delivers
+    fn capabilities(&self) -> Capabilities;
+
+    fn matches(&self, vendor_id: u16, product_id: u16) -> bool {
+        self.profile().matches_id(vendor_id, product_id)
+    }
+
+    /// Finds the card on `bus` and sets it up until it is ready to capture.
+    ///
+    /// The bus is kept to find the card again if a session loses it.
+    fn connect(&mut self, bus: &Arc<dyn UsbBus>, config: &ConnectConfig) -> Result<DeviceInfo, Error>;
+
+    /// Starts streaming frames to `data_callback` on a new session.
+    ///
+    /// With `record_to` set the raw data endpoint is also written to that file, see
+    /// `RecordingWriter`. Backends that don't stream bulk transfers return `Error::Unsupported`.
+    fn start(
+        &self,
+        data_callback: DataCallback,
+        transfer_config: TransferConfig,
+        auto_recover: bool,
+        record_to: Option<&Path>,
+    ) -> Result<CaptureSession, Error>;
+
+    /// Tells an idle card to stop sending frames, fails with `Error::NotConnected` while a
+    /// session owns the device.
+    fn stop(&self) -> Result<(), Error>;
+
+    fn read_eeprom(&self) -> Result<EepromDump, Error> {
+        Err(Error::Unsupported("eeprom access"))
+    }
+
+    fn restore_eeprom(&self, _dump: &EepromDump, _backup: &EepromDump) -> Result<(), Error> {
+        Err(Error::Unsupported("eeprom access"))
+    }
+}
 
 pub trait Capture {
-    fn connect<T: UsbContext>(context: &mut T) -> Result<DeviceHandle<T>, SimpleError>;
+    fn connect(
+        bus: &dyn UsbBus,
+        config: &ConnectConfig,
+        profile: &DeviceProfile,
+    ) -> Result<(UsbHandle, DeviceInfo), Error>;
 
-    fn open_device<T: UsbContext>(
-        context: &mut T,
+    /// Polls the bus until a device with `vid`/`pid` can be opened, e.g. after a firmware upload.
+    fn wait_for_device(
+        bus: &dyn UsbBus,
         vid: u16,
         pid: u16,
-    ) -> Option<(Device<T>, DeviceDescriptor, DeviceHandle<T>)> {
-        let devices = match context.devices() {
-            Ok(d) => d,
-            Err(_) => return None,
-        };
-    
-        for device in devices.iter() {
-            let device_desc = match device.device_descriptor() {
-                Ok(d) => d,
-                Err(_) => continue,
-            };
-    
-            if device_desc.vendor_id() == vid && device_desc.product_id() == pid {
-                match device.open() {
-                    Ok(handle) => return Some((device, device_desc, handle)),
-                    Err(e) => panic!("Device found but failed to open: {}", e),
-                }
+        timeout: Duration,
+    ) -> Result<OpenDevice, Error> {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            match bus.open(UsbSelector::id(vid, pid)) {
+                Ok(Some(found)) => return Ok(found),
+                Ok(None) => {}
+                // the OS can list the device a moment before it lets us open it
+                Err(Error::AccessDenied) | Err(Error::DeviceOpen(_))
+                    if Instant::now() < deadline => {}
+                Err(err) => return Err(err),
             }
+
+            if Instant::now() >= deadline {
+                return Err(Error::ReEnumerationTimeout);
+            }
+
+            thread::sleep(POLL_INTERVAL);
         }
-    
-        None
     }
 }
-
@@ -0,0 +1,134 @@
+use std::sync::Arc;
+
+use super::bus::{UsbBus, UsbSelector};
+use super::devices::{DeviceProfile, DeviceTable};
+use super::katsukitty::Katsukity;
+#[cfg(feature = "experimental-loopy-n3dsxl")]
+use super::loopy::LoopyN3dsxl;
+use super::loopy_ds::LoopyDs;
+use super::{CaptureBackend, ConnectConfig};
+use crate::{DeviceInfo, Error};
+
+/// A backend the library knows how to build.
+pub struct BackendEntry {
+    pub name: &'static str,
+    create: fn(DeviceProfile) -> Box<dyn CaptureBackend>,
+}
+
+impl BackendEntry {
+    /// Builds the backend for one entry of the device table.
+    pub fn create(&self, profile: DeviceProfile) -> Box<dyn CaptureBackend> {
+        (self.create)(profile)
+    }
+}
+
+const BACKENDS: &[BackendEntry] = &[
+    BackendEntry {
+        name: "Katsukity",
+        create: |profile| Box::new(Katsukity::new(profile)),
+    },
+    #[cfg(feature = "experimental-loopy-n3dsxl")]
+    BackendEntry {
+        name: "Loopy N3DSXL",
+        create: |profile| Box::new(LoopyN3dsxl::new(profile)),
+    },
+    BackendEntry {
+        name: "Loopy DS",
+        create: |profile| Box::new(LoopyDs::new(profile)),
+    },
+];
+
+// backends the device table may name but that were left out of this build
+const DISABLED_BACKENDS: &[&str] = &[
+    #[cfg(not(feature = "experimental-loopy-n3dsxl"))]
+    "Loopy N3DSXL",
+];
+
+/// Every backend compiled into the library.
+pub fn backends() -> &'static [BackendEntry] {
+    BACKENDS
+}
+
+/// The backend with this name, as used by the `backend` field of the device table.
+pub fn backend_named(name: &str) -> Option<&'static BackendEntry> {
+    BACKENDS.iter().find(|backend| backend.name == name)
+}
+
+/// Every VID/PID pair the device table knows how to talk to with the backends in this build.
+///
+/// The table is the built in one plus the file named in `CAPPY3DS_DEVICE_TABLE`, a broken file
+/// is an error just like it is for `connect_any`.
+pub fn supported_devices() -> Result<Vec<(u16, u16)>, Error> {
+    let table = DeviceTable::builtin().with_env()?;
+
+    Ok(table
+        .devices()
+        .iter()
+        .filter(|profile| backend_named(&profile.backend).is_some())
+        .flat_map(|profile| profile.device_ids())
+        .collect())
+}
+
+/// Looks at what is plugged in and connects the first card a backend accepts.
+///
+/// If a card was found but could not be set up, that error is returned rather than
+/// `Error::DeviceNotFound`.
+pub fn connect_any(
+    bus: &Arc<dyn UsbBus>,
+    config: &ConnectConfig,
+) -> Result<(Box<dyn CaptureBackend>, DeviceInfo), Error> {
+    let table = config.devices.with_env()?;
+    let devices = bus.devices()?;
+
+    let mut tried = Vec::new();
+    let mut first_error = None;
+
+    for device in devices {
+        let (vendor_id, product_id) = (device.vendor_id, device.product_id);
+
+        // only open devices whose entries need the product string to tell them apart
+        let product = if table.needs_product(vendor_id, product_id) {
+            bus.open(UsbSelector::Device(device))
+                .ok()
+                .flatten()
+                .and_then(|device| device.description.product)
+        } else {
+            None
+        };
+
+        let profile = match table.find(vendor_id, product_id, product.as_deref()) {
+            Some(profile) => profile,
+            None => continue,
+        };
+
+        let entry = match backend_named(&profile.backend) {
+            Some(entry) => entry,
+            None if DISABLED_BACKENDS.contains(&profile.backend.as_str()) => {
+                log::debug!("{}: {} is not in this build", profile.name, profile.backend);
+                continue;
+            }
+            None => {
+                log::warn!("{}: unknown backend {:?}", profile.name, profile.backend);
+                continue;
+            }
+        };
+
+        // backends search the bus themselves, one attempt each is enough
+        if tried.contains(&&profile.name) {
+            continue;
+        }
+        tried.push(&profile.name);
+
+        let mut backend = entry.create(profile.clone());
+        match backend.connect(bus, config) {
+            Ok(info) => return Ok((backend, info)),
+            Err(Error::DeviceNotFound) => {}
+            Err(err) => {
+                log::warn!("{}: {}", profile.name, err);
+                first_error.get_or_insert(err);
+            }
+        }
+    }
+
+    Err(first_error.unwrap_or(Error::DeviceNotFound))
+}
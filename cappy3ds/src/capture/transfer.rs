@@ -0,0 +1,432 @@
+use std::ffi::c_void;
+use std::marker::PhantomData;
+use std::slice;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::SyncSender;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rusb::ffi as usbffi;
+use rusb::{DeviceHandle, UsbContext};
+use usbffi::constants::*;
+
+use crate::session::CaptureEvent;
+use crate::Error;
+
+// generic errors in a row before we give up on the stream
+const MAX_CONSECUTIVE_ERRORS: usize = 10;
+
+/// Size and number of the bulk transfers kept queued while capturing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransferConfig {
+    /// Bytes requested by each transfer
+    pub transfer_size: usize,
+    /// How many transfers are kept submitted at once
+    pub queue_depth: usize,
+    /// libusb timeout for a single transfer
+    pub timeout: Duration,
+}
+
+impl Default for TransferConfig {
+    fn default() -> Self {
+        Self {
+            transfer_size: 0x4000,
+            queue_depth: 10,
+            timeout: Duration::from_millis(1000),
+        }
+    }
+}
+
+struct PoolState<H> {
+    handler: Mutex<H>,
+    events: SyncSender<CaptureEvent>,
+    stopping: AtomicBool,
+    disconnected: AtomicBool,
+    consecutive_errors: AtomicUsize,
+    in_flight: AtomicUsize,
+    // transfers parked after a stall, waiting for the halt to be cleared
+    stalled: Mutex<Vec<*mut usbffi::libusb_transfer>>,
+}
+
+/// A set of libusb bulk transfers that keep resubmitting themselves into one handler.
+///
+/// The pool owns the transfer structs, their buffers and the handler for as long as it lives,
+/// and borrows the device handle so it can't outlive it. Dropping the pool cancels everything
+/// still queued and waits for libusb to hand the transfers back before freeing them.
+pub(crate) struct TransferPool<'a, H> {
+    context: *mut usbffi::libusb_context,
+    endpoint: u8,
+    transfers: Vec<*mut usbffi::libusb_transfer>,
+    // only touched by libusb through the pointers in `transfers`
+    _buffers: Vec<Box<[u8]>>,
+    // boxed so the transfers can point at it, freed in drop
+    state: *mut PoolState<H>,
+    _handle: PhantomData<&'a ()>,
+}
+
+impl<'a, H> TransferPool<'a, H>
+where
+    H: FnMut(&[u8]) + Send,
+{
+    pub fn new<T: UsbContext>(
+        handle: &'a DeviceHandle<T>,
+        endpoint: u8,
+        config: TransferConfig,
+        handler: H,
+        events: SyncSender<CaptureEvent>,
+    ) -> Result<Self, Error> {
+        let length = config
+            .transfer_size
+            .try_into()
+            .map_err(|_| Error::TransferFailed(rusb::Error::InvalidParam))?;
+        let timeout = config.timeout.as_millis().try_into().unwrap_or(u32::MAX);
+
+        let mut pool = Self {
+            context: handle.context().as_raw(),
+            endpoint,
+            transfers: Vec::with_capacity(config.queue_depth),
+            _buffers: Vec::with_capacity(config.queue_depth),
+            state: Box::into_raw(Box::new(PoolState {
+                handler: Mutex::new(handler),
+                events,
+                stopping: AtomicBool::new(false),
+                disconnected: AtomicBool::new(false),
+                consecutive_errors: AtomicUsize::new(0),
+                in_flight: AtomicUsize::new(0),
+                stalled: Mutex::new(Vec::new()),
+            })),
+            _handle: PhantomData,
+        };
+
+        let user_data = pool.state as *mut c_void;
+
+        for _ in 0..config.queue_depth {
+            let transfer = unsafe { usbffi::libusb_alloc_transfer(0) };
+            if transfer.is_null() {
+                return Err(Error::TransferFailed(rusb::Error::NoMem));
+            }
+            pool.transfers.push(transfer);
+
+            let mut buffer = vec![0u8; config.transfer_size].into_boxed_slice();
+
+            unsafe {
+                usbffi::libusb_fill_bulk_transfer(
+                    transfer,
+                    handle.as_raw(),
+                    endpoint,
+                    buffer.as_mut_ptr(),
+                    length,
+                    transfer_finished::<H> as _,
+                    user_data,
+                    timeout,
+                );
+            }
+
+            pool._buffers.push(buffer);
+        }
+
+        Ok(pool)
+    }
+
+    fn state(&self) -> &PoolState<H> {
+        unsafe { &*self.state }
+    }
+
+    /// Queues every transfer in the pool.
+    pub fn submit(&self) -> Result<(), Error> {
+        for transfer in &self.transfers {
+            self.state().in_flight.fetch_add(1, Ordering::SeqCst);
+
+            let result = unsafe { usbffi::libusb_submit_transfer(*transfer) };
+            if result != 0 {
+                self.state().in_flight.fetch_sub(1, Ordering::SeqCst);
+                return Err(Error::TransferFailed(usb_error(result)));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Deals with anything the transfer callbacks couldn't handle from inside the event loop.
+    ///
+    /// Clears the halt on a stalled endpoint and requeues the transfers parked by it, and
+    /// reports whether the stream is still alive.
+    pub fn recover<T: UsbContext>(&self, handle: &DeviceHandle<T>) -> Result<(), Error> {
+        let state = self.state();
+
+        if state.disconnected.load(Ordering::SeqCst) {
+            return Err(Error::Disconnected);
+        }
+
+        if state.consecutive_errors.load(Ordering::SeqCst) >= MAX_CONSECUTIVE_ERRORS {
+            return Err(Error::TransferFailed(rusb::Error::Io));
+        }
+
+        let stalled: Vec<_> = state.stalled.lock().unwrap().drain(..).collect();
+        if stalled.is_empty() {
+            return Ok(());
+        }
+
+        match handle.clear_halt(self.endpoint) {
+            Ok(_) => {}
+            Err(rusb::Error::NoDevice) => return Err(Error::Disconnected),
+            Err(err) => return Err(Error::TransferFailed(err)),
+        }
+
+        for transfer in stalled {
+            state.in_flight.fetch_add(1, Ordering::SeqCst);
+
+            let result = unsafe { usbffi::libusb_submit_transfer(transfer) };
+            if result != 0 {
+                state.in_flight.fetch_sub(1, Ordering::SeqCst);
+                return Err(match result {
+                    LIBUSB_ERROR_NO_DEVICE => Error::Disconnected,
+                    result => Error::TransferFailed(usb_error(result)),
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a, H> Drop for TransferPool<'a, H> {
+    fn drop(&mut self) {
+        let state = unsafe { &*self.state };
+
+        state.stopping.store(true, Ordering::SeqCst);
+
+        for transfer in &self.transfers {
+            unsafe {
+                usbffi::libusb_cancel_transfer(*transfer);
+            }
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while state.in_flight.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+            handle_events(self.context, Duration::from_millis(100));
+        }
+
+        if state.in_flight.load(Ordering::SeqCst) > 0 {
+            // libusb still owns some of these, freeing anything now would be a use after free
+            log::warn!("transfers did not finish cancelling, leaking them");
+            std::mem::forget(std::mem::take(&mut self._buffers));
+            return;
+        }
+
+        for transfer in self.transfers.drain(..) {
+            unsafe {
+                usbffi::libusb_free_transfer(transfer);
+            }
+        }
+
+        drop(unsafe { Box::from_raw(self.state) });
+    }
+}
+
+/// What a finished transfer means for the pool, decided from its libusb status alone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Completion {
+    /// Cancelled or the pool is stopping, the transfer goes back to the pool
+    Retired,
+    /// Data arrived and the error streak is over
+    Completed,
+    /// Whatever arrived before the timeout is still good
+    TimedOut,
+    /// The device is gone, nothing gets resubmitted
+    Disconnected,
+    /// Parked until `recover()` clears the halt
+    Stalled,
+    /// The data is dropped and the transfer counts as an error
+    Overflowed,
+    /// Any other failure, counts as an error
+    Failed,
+}
+
+impl Completion {
+    fn of(status: i32, stopping: bool) -> Self {
+        if stopping {
+            return Completion::Retired;
+        }
+
+        match status {
+            LIBUSB_TRANSFER_CANCELLED => Completion::Retired,
+            LIBUSB_TRANSFER_COMPLETED => Completion::Completed,
+            LIBUSB_TRANSFER_TIMED_OUT => Completion::TimedOut,
+            LIBUSB_TRANSFER_NO_DEVICE => Completion::Disconnected,
+            LIBUSB_TRANSFER_STALL => Completion::Stalled,
+            LIBUSB_TRANSFER_OVERFLOW => Completion::Overflowed,
+            _ => Completion::Failed,
+        }
+    }
+
+    /// The event reported to the session when a transfer finishes this way.
+    fn event(self) -> Option<CaptureEvent> {
+        match self {
+            Completion::Retired | Completion::Completed => None,
+            Completion::TimedOut => Some(CaptureEvent::Timeout),
+            Completion::Disconnected => Some(CaptureEvent::Disconnected),
+            Completion::Stalled => Some(CaptureEvent::Stall),
+            Completion::Overflowed => Some(CaptureEvent::Overflow),
+            Completion::Failed => Some(CaptureEvent::TransferError),
+        }
+    }
+}
+
+extern "system" fn transfer_finished<H>(transfer_ptr: *mut usbffi::libusb_transfer)
+where
+    H: FnMut(&[u8]) + Send,
+{
+    let transfer: &mut usbffi::libusb_transfer = unsafe { &mut *transfer_ptr };
+
+    let state = unsafe { &*(transfer.user_data as *const PoolState<H>) };
+
+    let completion = Completion::of(transfer.status, state.stopping.load(Ordering::SeqCst));
+
+    if let Some(event) = completion.event() {
+        match completion {
+            // only the first transfer to notice reports the unplug
+            Completion::Disconnected if state.disconnected.swap(true, Ordering::SeqCst) => {}
+            _ => {
+                let _ = state.events.try_send(event);
+            }
+        }
+    }
+
+    let deliver = match completion {
+        Completion::Retired | Completion::Disconnected => {
+            state.in_flight.fetch_sub(1, Ordering::SeqCst);
+            return;
+        }
+        Completion::Stalled => {
+            // the halt can't be cleared from inside the event loop, park it for recover()
+            state.stalled.lock().unwrap().push(transfer_ptr);
+            state.in_flight.fetch_sub(1, Ordering::SeqCst);
+            return;
+        }
+        Completion::Completed => {
+            state.consecutive_errors.store(0, Ordering::SeqCst);
+            true
+        }
+        Completion::TimedOut => true,
+        Completion::Overflowed | Completion::Failed => {
+            state.consecutive_errors.fetch_add(1, Ordering::SeqCst);
+            false
+        }
+    };
+
+    let actual_length = transfer.actual_length.clamp(0, transfer.length) as usize;
+
+    if deliver && actual_length > 0 {
+        let data = unsafe { slice::from_raw_parts(transfer.buffer, actual_length) };
+
+        (state.handler.lock().unwrap())(data);
+    }
+
+    if state.stopping.load(Ordering::SeqCst)
+        || state.consecutive_errors.load(Ordering::SeqCst) >= MAX_CONSECUTIVE_ERRORS
+    {
+        state.in_flight.fetch_sub(1, Ordering::SeqCst);
+        return;
+    }
+
+    let result = unsafe { usbffi::libusb_submit_transfer(transfer_ptr) };
+    if result != 0 {
+        state.in_flight.fetch_sub(1, Ordering::SeqCst);
+
+        if result == LIBUSB_ERROR_NO_DEVICE && !state.disconnected.swap(true, Ordering::SeqCst) {
+            let _ = state.events.try_send(CaptureEvent::Disconnected);
+        }
+    }
+}
+
+/// Runs libusb's event loop once, dispatching any finished transfers.
+pub(crate) fn handle_events(context: *mut usbffi::libusb_context, timeout: Duration) {
+    let timeout = libc::timeval {
+        tv_sec: timeout.as_secs() as _,
+        tv_usec: timeout.subsec_micros() as _,
+    };
+
+    unsafe {
+        usbffi::libusb_handle_events_timeout(context, &timeout as *const libc::timeval);
+    }
+}
+
+fn usb_error(code: i32) -> rusb::Error {
+    match code {
+        LIBUSB_ERROR_IO => rusb::Error::Io,
+        LIBUSB_ERROR_INVALID_PARAM => rusb::Error::InvalidParam,
+        LIBUSB_ERROR_ACCESS => rusb::Error::Access,
+        LIBUSB_ERROR_NO_DEVICE => rusb::Error::NoDevice,
+        LIBUSB_ERROR_NOT_FOUND => rusb::Error::NotFound,
+        LIBUSB_ERROR_BUSY => rusb::Error::Busy,
+        LIBUSB_ERROR_TIMEOUT => rusb::Error::Timeout,
+        LIBUSB_ERROR_OVERFLOW => rusb::Error::Overflow,
+        LIBUSB_ERROR_PIPE => rusb::Error::Pipe,
+        LIBUSB_ERROR_INTERRUPTED => rusb::Error::Interrupted,
+        LIBUSB_ERROR_NO_MEM => rusb::Error::NoMem,
+        LIBUSB_ERROR_NOT_SUPPORTED => rusb::Error::NotSupported,
+        _ => rusb::Error::Other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn statuses_map_to_completions_and_events() {
+        let cases = [
+            (LIBUSB_TRANSFER_COMPLETED, Completion::Completed, None),
+            (
+                LIBUSB_TRANSFER_TIMED_OUT,
+                Completion::TimedOut,
+                Some(CaptureEvent::Timeout),
+            ),
+            (
+                LIBUSB_TRANSFER_STALL,
+                Completion::Stalled,
+                Some(CaptureEvent::Stall),
+            ),
+            (
+                LIBUSB_TRANSFER_NO_DEVICE,
+                Completion::Disconnected,
+                Some(CaptureEvent::Disconnected),
+            ),
+            (
+                LIBUSB_TRANSFER_OVERFLOW,
+                Completion::Overflowed,
+                Some(CaptureEvent::Overflow),
+            ),
+            (
+                LIBUSB_TRANSFER_ERROR,
+                Completion::Failed,
+                Some(CaptureEvent::TransferError),
+            ),
+            (LIBUSB_TRANSFER_CANCELLED, Completion::Retired, None),
+        ];
+
+        for (status, completion, event) in cases {
+            assert_eq!(
+                Completion::of(status, false),
+                completion,
+                "status {}",
+                status
+            );
+            assert_eq!(completion.event(), event, "status {}", status);
+        }
+    }
+
+    #[test]
+    fn stopping_retires_every_transfer() {
+        for status in [
+            LIBUSB_TRANSFER_COMPLETED,
+            LIBUSB_TRANSFER_TIMED_OUT,
+            LIBUSB_TRANSFER_STALL,
+            LIBUSB_TRANSFER_NO_DEVICE,
+            LIBUSB_TRANSFER_ERROR,
+        ] {
+            assert_eq!(Completion::of(status, true), Completion::Retired);
+        }
+    }
+}
@@ -0,0 +1,283 @@
+use std::collections::VecDeque;
+use std::sync::mpsc::SyncSender;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use super::{BulkStream, StreamHandler, Transport};
+use crate::capture::transfer::TransferConfig;
+use crate::session::CaptureEvent;
+use crate::Error;
+
+// longest a read with nothing queued pretends to wait
+const IDLE_WAIT: Duration = Duration::from_millis(10);
+
+/// One step of a `MockTransport` script.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Exchange {
+    /// A control transfer the host must send, with exactly this payload
+    ControlOut {
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: Vec<u8>,
+    },
+    /// A control transfer the host must ask for, answered with `response`
+    ControlIn {
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        response: Vec<u8>,
+    },
+    /// A bulk write the host must make
+    BulkOut { endpoint: u8, data: Vec<u8> },
+    /// Data waiting on a bulk IN endpoint, for `read_bulk` or a stream
+    BulkIn { endpoint: u8, data: Vec<u8> },
+    /// The next transfer of any kind fails with this error
+    Fail(rusb::Error),
+}
+
+/// An in-memory device that plays back a script of expected requests and canned responses.
+///
+/// Writes and control transfers have to match the next step exactly or the mock panics, so
+/// a test fails at the first request that differs. Bulk reads only take the next step if it
+/// is data for their endpoint and time out otherwise, like a real device with nothing to say.
+#[derive(Debug, Default)]
+pub struct MockTransport {
+    script: Mutex<VecDeque<Exchange>>,
+    claimed: Mutex<Vec<u8>>,
+}
+
+impl MockTransport {
+    pub fn new(script: impl IntoIterator<Item = Exchange>) -> Self {
+        Self {
+            script: Mutex::new(script.into_iter().collect()),
+            claimed: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Adds steps to the end of the script.
+    pub fn push(&self, exchanges: impl IntoIterator<Item = Exchange>) {
+        self.script.lock().unwrap().extend(exchanges);
+    }
+
+    /// Steps that have not happened yet.
+    pub fn remaining(&self) -> Vec<Exchange> {
+        self.script.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Panics unless every step of the script has been played.
+    pub fn assert_done(&self) {
+        let remaining = self.remaining();
+        assert!(remaining.is_empty(), "script not finished, still expecting {:?}", remaining);
+    }
+
+    /// Interfaces currently claimed.
+    pub fn claimed_interfaces(&self) -> Vec<u8> {
+        self.claimed.lock().unwrap().clone()
+    }
+
+    // takes the next step, which the caller says must be there
+    fn expect(&self, what: &str) -> Exchange {
+        self.script
+            .lock()
+            .unwrap()
+            .pop_front()
+            .unwrap_or_else(|| panic!("script finished, but got {}", what))
+    }
+
+    // copies waiting data on `endpoint` into `buf`, anything that does not fit stays queued
+    fn take_bulk_in(&self, endpoint: u8, buf: &mut [u8]) -> Option<rusb::Result<usize>> {
+        let mut script = self.script.lock().unwrap();
+
+        match script.front_mut()? {
+            Exchange::Fail(err) => {
+                let err = *err;
+                script.pop_front();
+                Some(Err(err))
+            }
+            Exchange::BulkIn {
+                endpoint: expected,
+                data,
+            } if *expected == endpoint => {
+                let len = data.len().min(buf.len());
+                buf[..len].copy_from_slice(&data[..len]);
+                data.drain(..len);
+
+                if data.is_empty() {
+                    script.pop_front();
+                }
+
+                Some(Ok(len))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Transport for MockTransport {
+    fn set_active_configuration(&self, _config: u8) -> rusb::Result<()> {
+        Ok(())
+    }
+
+    fn claim_interface(&self, iface: u8) -> rusb::Result<()> {
+        let mut claimed = self.claimed.lock().unwrap();
+        if !claimed.contains(&iface) {
+            claimed.push(iface);
+        }
+
+        Ok(())
+    }
+
+    fn release_interface(&self, iface: u8) -> rusb::Result<()> {
+        self.claimed.lock().unwrap().retain(|claimed| *claimed != iface);
+
+        Ok(())
+    }
+
+    fn clear_halt(&self, _endpoint: u8) -> rusb::Result<()> {
+        Ok(())
+    }
+
+    fn read_control(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &mut [u8],
+        _timeout: Duration,
+    ) -> rusb::Result<usize> {
+        let got = format!(
+            "control in {:#04x} {:#04x} {:#06x} {:#06x}",
+            request_type, request, value, index
+        );
+
+        match self.expect(&got) {
+            Exchange::ControlIn {
+                request_type: expected_type,
+                request: expected_request,
+                value: expected_value,
+                index: expected_index,
+                response,
+            } if (expected_type, expected_request, expected_value, expected_index)
+                == (request_type, request, value, index) =>
+            {
+                let len = response.len().min(buf.len());
+                buf[..len].copy_from_slice(&response[..len]);
+                Ok(len)
+            }
+            Exchange::Fail(err) => Err(err),
+            expected => panic!("expected {:?}, got {}", expected, got),
+        }
+    }
+
+    fn write_control(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &[u8],
+        _timeout: Duration,
+    ) -> rusb::Result<usize> {
+        let got = Exchange::ControlOut {
+            request_type,
+            request,
+            value,
+            index,
+            data: buf.to_vec(),
+        };
+
+        match self.expect(&format!("{:?}", got)) {
+            Exchange::Fail(err) => Err(err),
+            expected if expected == got => Ok(buf.len()),
+            expected => panic!("expected {:?}, got {:?}", expected, got),
+        }
+    }
+
+    fn read_bulk(&self, endpoint: u8, buf: &mut [u8], timeout: Duration) -> rusb::Result<usize> {
+        self.take_bulk_in(endpoint, buf).unwrap_or_else(|| {
+            // a real read blocks until the timeout, keep polling callers from spinning
+            thread::sleep(timeout.min(IDLE_WAIT));
+            Err(rusb::Error::Timeout)
+        })
+    }
+
+    fn write_bulk(&self, endpoint: u8, buf: &[u8], _timeout: Duration) -> rusb::Result<usize> {
+        let got = Exchange::BulkOut {
+            endpoint,
+            data: buf.to_vec(),
+        };
+
+        match self.expect(&format!("{:?}", got)) {
+            Exchange::Fail(err) => Err(err),
+            expected if expected == got => Ok(buf.len()),
+            expected => panic!("expected {:?}, got {:?}", expected, got),
+        }
+    }
+
+    fn stream<'a>(
+        &'a self,
+        endpoint: u8,
+        config: TransferConfig,
+        handler: StreamHandler<'a>,
+        events: SyncSender<CaptureEvent>,
+    ) -> Result<Box<dyn BulkStream + 'a>, Error> {
+        Ok(Box::new(MockStream {
+            transport: self,
+            endpoint,
+            buf: vec![0; config.transfer_size],
+            handler,
+            events,
+        }))
+    }
+}
+
+struct MockStream<'a> {
+    transport: &'a MockTransport,
+    endpoint: u8,
+    buf: Vec<u8>,
+    handler: StreamHandler<'a>,
+    events: SyncSender<CaptureEvent>,
+}
+
+impl<'a> BulkStream for MockStream<'a> {
+    fn poll(&mut self, timeout: Duration) -> Result<(), Error> {
+        let mut delivered = false;
+
+        while let Some(result) = self.transport.take_bulk_in(self.endpoint, &mut self.buf) {
+            delivered = true;
+
+            // same outcomes as the libusb transfer pool
+            match result {
+                Ok(len) => (self.handler)(&self.buf[..len]),
+                Err(rusb::Error::Timeout) => {
+                    let _ = self.events.try_send(CaptureEvent::Timeout);
+                }
+                Err(rusb::Error::Pipe) => {
+                    let _ = self.events.try_send(CaptureEvent::Stall);
+                }
+                Err(rusb::Error::Overflow) => {
+                    let _ = self.events.try_send(CaptureEvent::Overflow);
+                }
+                Err(rusb::Error::NoDevice) => {
+                    let _ = self.events.try_send(CaptureEvent::Disconnected);
+                    return Err(Error::Disconnected);
+                }
+                Err(_) => {
+                    let _ = self.events.try_send(CaptureEvent::TransferError);
+                }
+            }
+        }
+
+        // nothing queued, don't spin the capture loop
+        if !delivered {
+            thread::sleep(timeout.min(IDLE_WAIT));
+        }
+
+        Ok(())
+    }
+}
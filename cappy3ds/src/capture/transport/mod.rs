@@ -0,0 +1,154 @@
+use std::sync::mpsc::SyncSender;
+use std::time::Duration;
+
+use rusb::{DeviceHandle, UsbContext};
+
+use super::transfer::{self, TransferConfig, TransferPool};
+use crate::session::CaptureEvent;
+use crate::Error;
+
+#[cfg(any(test, feature = "test-support"))]
+mod mock;
+
+#[cfg(any(test, feature = "test-support"))]
+pub use mock::{Exchange, MockTransport};
+
+/// Called with the contents of every finished streaming transfer.
+pub type StreamHandler<'a> = Box<dyn FnMut(&[u8]) + Send + 'a>;
+
+/// Everything a backend does with an open USB device.
+///
+/// Implemented for `rusb::DeviceHandle` to talk to real cards and by `MockTransport` to run the
+/// same code against a script. The blocking calls mirror the `DeviceHandle` methods of the
+/// same name.
+pub trait Transport {
+    fn set_active_configuration(&self, config: u8) -> rusb::Result<()>;
+
+    fn claim_interface(&self, iface: u8) -> rusb::Result<()>;
+
+    fn release_interface(&self, iface: u8) -> rusb::Result<()>;
+
+    fn clear_halt(&self, endpoint: u8) -> rusb::Result<()>;
+
+    fn read_control(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> rusb::Result<usize>;
+
+    fn write_control(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &[u8],
+        timeout: Duration,
+    ) -> rusb::Result<usize>;
+
+    fn read_bulk(&self, endpoint: u8, buf: &mut [u8], timeout: Duration) -> rusb::Result<usize>;
+
+    fn write_bulk(&self, endpoint: u8, buf: &[u8], timeout: Duration) -> rusb::Result<usize>;
+
+    /// Keeps bulk reads queued on `endpoint` and feeds what arrives to `handler`.
+    ///
+    /// Nothing is delivered until the returned stream is polled, dropping it cancels whatever
+    /// is still queued.
+    fn stream<'a>(
+        &'a self,
+        endpoint: u8,
+        config: TransferConfig,
+        handler: StreamHandler<'a>,
+        events: SyncSender<CaptureEvent>,
+    ) -> Result<Box<dyn BulkStream + 'a>, Error>;
+}
+
+/// Bulk reads kept in flight by `Transport::stream`.
+pub trait BulkStream {
+    /// Waits up to `timeout` for transfers to finish and hands them to the handler.
+    ///
+    /// Stalls and single failed transfers are dealt with here and reported as events, an
+    /// error means the stream is over.
+    fn poll(&mut self, timeout: Duration) -> Result<(), Error>;
+}
+
+impl<T: UsbContext> Transport for DeviceHandle<T> {
+    fn set_active_configuration(&self, config: u8) -> rusb::Result<()> {
+        DeviceHandle::set_active_configuration(self, config)
+    }
+
+    fn claim_interface(&self, iface: u8) -> rusb::Result<()> {
+        DeviceHandle::claim_interface(self, iface)
+    }
+
+    fn release_interface(&self, iface: u8) -> rusb::Result<()> {
+        DeviceHandle::release_interface(self, iface)
+    }
+
+    fn clear_halt(&self, endpoint: u8) -> rusb::Result<()> {
+        DeviceHandle::clear_halt(self, endpoint)
+    }
+
+    fn read_control(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> rusb::Result<usize> {
+        DeviceHandle::read_control(self, request_type, request, value, index, buf, timeout)
+    }
+
+    fn write_control(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &[u8],
+        timeout: Duration,
+    ) -> rusb::Result<usize> {
+        DeviceHandle::write_control(self, request_type, request, value, index, buf, timeout)
+    }
+
+    fn read_bulk(&self, endpoint: u8, buf: &mut [u8], timeout: Duration) -> rusb::Result<usize> {
+        DeviceHandle::read_bulk(self, endpoint, buf, timeout)
+    }
+
+    fn write_bulk(&self, endpoint: u8, buf: &[u8], timeout: Duration) -> rusb::Result<usize> {
+        DeviceHandle::write_bulk(self, endpoint, buf, timeout)
+    }
+
+    fn stream<'a>(
+        &'a self,
+        endpoint: u8,
+        config: TransferConfig,
+        handler: StreamHandler<'a>,
+        events: SyncSender<CaptureEvent>,
+    ) -> Result<Box<dyn BulkStream + 'a>, Error> {
+        let pool = TransferPool::new(self, endpoint, config, handler, events)?;
+        pool.submit()?;
+
+        Ok(Box::new(RusbStream { handle: self, pool }))
+    }
+}
+
+// libusb async transfers, driven by running the context's event loop
+struct RusbStream<'a, T: UsbContext> {
+    handle: &'a DeviceHandle<T>,
+    pool: TransferPool<'a, StreamHandler<'a>>,
+}
+
+impl<'a, T: UsbContext> BulkStream for RusbStream<'a, T> {
+    fn poll(&mut self, timeout: Duration) -> Result<(), Error> {
+        transfer::handle_events(self.handle.context().as_raw(), timeout);
+
+        self.pool.recover(self.handle)
+    }
+}
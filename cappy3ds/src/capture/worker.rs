@@ -0,0 +1,166 @@
+use std::sync::mpsc::{Receiver, SyncSender, TryRecvError};
+use std::sync::Arc;
+use std::{thread, time};
+
+use super::bus::{UsbBus, UsbHandle};
+use super::devices::DeviceProfile;
+use super::transport::Transport;
+use super::{Capture, ConnectConfig, HandleSlot};
+use crate::session::{CaptureEvent, CaptureSession, Command};
+use crate::Error;
+
+/// The card specific half of a capture session, see `spawn_capture` for the rest.
+pub(crate) trait CaptureDriver: Send + 'static {
+    /// Finds and sets up the card again after it was lost.
+    type Card: Capture;
+
+    /// Gets a freshly claimed or reconnected card sending frames, unless the session is paused.
+    fn start(&self, _handle: &dyn Transport, _profile: &DeviceProfile) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Delivers frames until `Command::Stop` arrives or the card fails, pausing and resuming
+    /// as asked.
+    fn read_frames(
+        &mut self,
+        handle: &dyn Transport,
+        profile: &DeviceProfile,
+        commands: &Receiver<Command>,
+        events: &SyncSender<CaptureEvent>,
+        paused: &mut bool,
+    ) -> Result<(), Error>;
+
+    /// Leaves the card idle before it is handed back to the backend.
+    fn stop(&self, _handle: &dyn Transport, _profile: &DeviceProfile) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Errors from `read_frames` that mean the card went away, these start a reconnect.
+    fn is_device_lost(&self, err: &Error) -> bool {
+        matches!(err, Error::Disconnected)
+    }
+
+    /// Errors from connecting that only mean the card is not back yet, on top of the usual ones.
+    fn is_not_ready(&self, _err: &Error) -> bool {
+        false
+    }
+}
+
+/// Claims the card in `device_handle` and runs `driver` on a new session.
+///
+/// With `auto_recover` set, losing the card sends `CaptureEvent::Reconnecting`, waits for it to
+/// come back and carries on with the same driver. Once the session ends the interfaces are
+/// released and the handle goes back into `device_handle`.
+pub(crate) fn spawn_capture<D: CaptureDriver>(
+    device_handle: HandleSlot,
+    bus: Arc<dyn UsbBus>,
+    connect_config: ConnectConfig,
+    profile: DeviceProfile,
+    auto_recover: bool,
+    mut driver: D,
+) -> Result<CaptureSession, Error> {
+    let handle = device_handle
+        .lock()
+        .unwrap()
+        .take()
+        .ok_or(Error::NotConnected)?;
+
+    let started = profile
+        .interfaces
+        .iter()
+        .try_for_each(|interface| handle.claim_interface(*interface))
+        .map_err(Error::from_open)
+        .and_then(|_| driver.start(&*handle, &profile));
+
+    if let Err(err) = started {
+        *device_handle.lock().unwrap() = Some(handle);
+        return Err(err);
+    }
+
+    Ok(CaptureSession::spawn(move |commands, events| {
+        let mut handle = handle;
+        let mut paused = false;
+
+        loop {
+            let result = driver.read_frames(&*handle, &profile, &commands, &events, &mut paused);
+
+            match result {
+                Err(err) if auto_recover && driver.is_device_lost(&err) => {
+                    log::info!("{}, waiting for the device to come back", err);
+                    let _ = events.try_send(CaptureEvent::Reconnecting);
+
+                    drop(handle);
+
+                    match reconnect(
+                        &driver,
+                        bus.as_ref(),
+                        &connect_config,
+                        &profile,
+                        &commands,
+                        &mut paused,
+                    )? {
+                        Some(new_handle) => {
+                            handle = new_handle;
+                            let _ = events.try_send(CaptureEvent::Reconnected);
+                        }
+                        // asked to stop while waiting, nothing left to tear down
+                        None => return Ok(()),
+                    }
+                }
+                result => {
+                    let stopped = driver.stop(&*handle, &profile);
+                    for interface in &profile.interfaces {
+                        let _ = handle.release_interface(*interface);
+                    }
+
+                    *device_handle.lock().unwrap() = Some(handle);
+
+                    return result.and(stopped);
+                }
+            }
+        }
+    }))
+}
+
+/// Waits for the card to show up again and reruns its connect handshake.
+///
+/// Returns `None` if the session was stopped while waiting.
+fn reconnect<D: CaptureDriver>(
+    driver: &D,
+    bus: &dyn UsbBus,
+    connect_config: &ConnectConfig,
+    profile: &DeviceProfile,
+    commands: &Receiver<Command>,
+    paused: &mut bool,
+) -> Result<Option<UsbHandle>, Error> {
+    let retry_delay = time::Duration::from_millis(500);
+
+    loop {
+        loop {
+            match commands.try_recv() {
+                Ok(Command::Pause) => *paused = true,
+                Ok(Command::Resume) => *paused = false,
+                Ok(Command::Stop) | Err(TryRecvError::Disconnected) => return Ok(None),
+                Err(TryRecvError::Empty) => break,
+            }
+        }
+
+        match D::Card::connect(bus, connect_config, profile) {
+            Ok((handle, _info)) => {
+                if !*paused {
+                    driver.start(&*handle, profile)?;
+                }
+                return Ok(Some(handle));
+            }
+            // still unplugged or half way through coming back
+            Err(Error::DeviceNotFound)
+            | Err(Error::ReEnumerationTimeout)
+            | Err(Error::AccessDenied)
+            | Err(Error::DeviceOpen(_)) => {}
+            Err(err) if driver.is_device_lost(&err) || driver.is_not_ready(&err) => {}
+            Err(err) => return Err(err),
+        }
+
+        thread::sleep(retry_delay);
+    }
+}
@@ -0,0 +1,79 @@
+use std::fmt;
+
+use crate::capture::bus::UsbDescription;
+
+/// What we could find out about a connected capture card.
+///
+/// The Katsukity EEPROM layout is undocumented, so the raw contents are kept alongside the few
+/// fields we can decode with confidence.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceInfo {
+    /// Product name reported by the card, falling back to the backend name
+    pub model: String,
+    /// `bcdDevice` from the USB device descriptor, as "major.minor.sub_minor"
+    pub hardware_revision: String,
+    /// USB serial number string, if the card has one
+    pub serial: Option<String>,
+    /// Printable text found in the EEPROM, usually a product or vendor tag
+    pub eeprom_text: Option<String>,
+    /// EEPROM contents as read from the card
+    pub eeprom: Vec<u8>,
+}
+
+impl DeviceInfo {
+    pub(crate) fn new(description: &UsbDescription, fallback_model: &str, eeprom: Vec<u8>) -> Self {
+        let model = description
+            .product
+            .as_ref()
+            .map(|model| model.trim().to_string())
+            .filter(|model| !model.is_empty())
+            .unwrap_or_else(|| fallback_model.to_string());
+
+        let serial = description
+            .serial
+            .as_ref()
+            .map(|serial| serial.trim().to_string())
+            .filter(|serial| !serial.is_empty());
+
+        let (major, minor, sub_minor) = description.device_version;
+
+        Self {
+            model,
+            hardware_revision: format!("{}.{}.{}", major, minor, sub_minor),
+            serial,
+            eeprom_text: longest_text(&eeprom),
+            eeprom,
+        }
+    }
+
+    /// A string that tells cards apart: the serial number if there is one, otherwise a
+    /// fingerprint of the EEPROM contents.
+    pub fn id(&self) -> String {
+        match &self.serial {
+            Some(serial) => serial.clone(),
+            None => format!("{:016x}", fnv1a(&self.eeprom)),
+        }
+    }
+}
+
+impl fmt::Display for DeviceInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} rev {} ({})", self.model, self.hardware_revision, self.id())
+    }
+}
+
+// shortest run of printable characters we treat as text rather than data
+const MIN_TEXT_LEN: usize = 4;
+
+fn longest_text(data: &[u8]) -> Option<String> {
+    data.split(|b| !(b.is_ascii_graphic() || *b == b' '))
+        .filter(|run| run.len() >= MIN_TEXT_LEN)
+        .max_by_key(|run| run.len())
+        .map(|run| String::from_utf8_lossy(run).trim().to_string())
+}
+
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
@@ -0,0 +1,91 @@
+use std::fs;
+use std::path::Path;
+
+use crate::Error;
+
+// "C3DSEEP" plus a format version
+const MAGIC: &[u8; 8] = b"C3DSEEP\x01";
+
+/// Size of the EEPROM behind the Katsukity 0x38 read command.
+pub const EEPROM_SIZE: usize = 128;
+
+/// A copy of a card's EEPROM that can be saved to disk and written back later.
+///
+/// On disk the dump is the magic, a little endian u16 length, the EEPROM bytes and a CRC-32 of
+/// everything before it, so a truncated or edited file is refused on load.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EepromDump {
+    data: Vec<u8>,
+}
+
+impl EepromDump {
+    pub(crate) fn new(data: Vec<u8>) -> Result<Self, Error> {
+        if data.len() != EEPROM_SIZE {
+            return Err(Error::InvalidEepromDump("wrong size"));
+        }
+
+        Ok(Self { data })
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// CRC-32 of the EEPROM contents, handy for comparing dumps by eye.
+    pub fn checksum(&self) -> u32 {
+        crc32(&self.data)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(MAGIC.len() + 2 + self.data.len() + 4);
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&(self.data.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(&self.data);
+        bytes.extend_from_slice(&crc32(&bytes).to_le_bytes());
+        bytes
+    }
+
+    /// Parses a dump written by `to_bytes`, checking the header, length and checksum.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let header_len = MAGIC.len() + 2;
+
+        if bytes.len() < header_len + 4 || &bytes[..MAGIC.len()] != MAGIC {
+            return Err(Error::InvalidEepromDump("not an eeprom dump"));
+        }
+
+        let len = u16::from_le_bytes([bytes[MAGIC.len()], bytes[MAGIC.len() + 1]]) as usize;
+        if bytes.len() != header_len + len + 4 {
+            return Err(Error::InvalidEepromDump("truncated"));
+        }
+
+        let (body, checksum) = bytes.split_at(header_len + len);
+        let checksum = u32::from_le_bytes([checksum[0], checksum[1], checksum[2], checksum[3]]);
+        if crc32(body) != checksum {
+            return Err(Error::InvalidEepromDump("checksum mismatch"));
+        }
+
+        Self::new(body[header_len..].to_vec())
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        fs::write(path, self.to_bytes()).map_err(Error::Io)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Self::from_bytes(&fs::read(path).map_err(Error::Io)?)
+    }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffffffffu32;
+
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb88320 & mask);
+        }
+    }
+
+    !crc
+}
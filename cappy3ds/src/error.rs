@@ -0,0 +1,138 @@
+use std::fmt;
+
+/// Everything that can go wrong while finding, configuring or streaming from a capture card.
+#[derive(Debug)]
+pub enum Error {
+    /// libusb could not be initialized or the bus could not be enumerated
+    UsbInit(rusb::Error),
+    /// No supported capture card is plugged in
+    DeviceNotFound,
+    /// A capture card was found but the OS would not let us open or claim it
+    AccessDenied,
+    /// A capture card was found but opening it failed for another reason
+    DeviceOpen(rusb::Error),
+    /// A control transfer failed while uploading the FX2 firmware
+    FirmwareUpload(rusb::Error),
+    /// A firmware or bitstream file could not be found or read
+    FirmwareMissing(String),
+    /// A firmware or bitstream file is not one we know works, it may be truncated or for another card
+    FirmwareDigestMismatch { name: String, sha256: String },
+    /// A firmware image could not be parsed
+    InvalidFirmware(String),
+    /// FX2 RAM read back different data than was uploaded, at the given address
+    FirmwareVerify(u16),
+    /// The FX2 never came back as the programmed device after the firmware upload
+    ReEnumerationTimeout,
+    /// Sending the FPGA configuration or bitstream failed
+    FpgaConfig(rusb::Error),
+    /// The FPGA never answered after being configured
+    FpgaTimeout,
+    /// The FPGA answered with something other than its "(C)tan" banner after configuration
+    FpgaBanner(Vec<u8>),
+    /// A USB transfer failed outside of device configuration
+    TransferFailed(rusb::Error),
+    /// The capture card went away while capturing
+    Disconnected,
+    /// Capture was requested before a successful connect
+    NotConnected,
+    /// The connected card can't do what was asked, e.g. an EEPROM backup on a Loopy DS
+    Unsupported(&'static str),
+    /// A device table file could not be parsed
+    InvalidDeviceTable(String),
+    /// Reading or writing a file failed
+    Io(std::io::Error),
+    /// An EEPROM dump failed to verify and was not used
+    InvalidEepromDump(&'static str),
+    /// The card's EEPROM differs from the backup given to confirm a restore, nothing was written
+    EepromBackupMismatch,
+    /// The EEPROM read back different data than was written
+    EepromVerifyFailed,
+    /// A USB recording could not be read back
+    InvalidRecording(&'static str),
+    /// The capture thread panicked instead of returning
+    CaptureThreadPanicked,
+}
+
+impl Error {
+    /// Maps a failure from `Device::open` or `claim_interface` to the variant a frontend cares about.
+    pub(crate) fn from_open(err: rusb::Error) -> Self {
+        match err {
+            rusb::Error::Access | rusb::Error::Busy => Error::AccessDenied,
+            rusb::Error::NoDevice | rusb::Error::NotFound => Error::DeviceNotFound,
+            err => Error::DeviceOpen(err),
+        }
+    }
+
+    /// Maps a failed transfer on an open device, telling an unplugged card apart from the rest.
+    pub(crate) fn from_transfer(err: rusb::Error) -> Self {
+        match err {
+            rusb::Error::NoDevice => Error::Disconnected,
+            err => Error::TransferFailed(err),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::UsbInit(err) => write!(f, "could not initialize libusb: {}", err),
+            Error::DeviceNotFound => write!(f, "no supported capture device found"),
+            Error::AccessDenied => write!(
+                f,
+                "capture device found but access was denied, check permissions or close other capture software"
+            ),
+            Error::DeviceOpen(err) => write!(f, "capture device found but failed to open: {}", err),
+            Error::FirmwareUpload(err) => write!(f, "could not upload FX2 firmware: {}", err),
+            Error::FirmwareMissing(reason) => write!(f, "firmware not available: {}", reason),
+            Error::FirmwareDigestMismatch { name, sha256 } => write!(
+                f,
+                "{} does not match any known good file (sha256 {})",
+                name, sha256
+            ),
+            Error::InvalidFirmware(reason) => write!(f, "invalid firmware image: {}", reason),
+            Error::FirmwareVerify(address) => {
+                write!(f, "FX2 firmware readback mismatch at {:#06x}", address)
+            }
+            Error::ReEnumerationTimeout => write!(
+                f,
+                "secondary device did not show up in time, firmware upload failed?"
+            ),
+            Error::FpgaConfig(err) => write!(f, "could not program fpga: {}", err),
+            Error::FpgaTimeout => write!(f, "fpga did not respond after configuration"),
+            Error::FpgaBanner(response) => write!(
+                f,
+                "fpga answered {:02X?} instead of its banner, bitstream not accepted",
+                response
+            ),
+            Error::TransferFailed(err) => write!(f, "usb transfer failed: {}", err),
+            Error::Disconnected => write!(f, "capture device was disconnected"),
+            Error::NotConnected => write!(f, "no capture device connected"),
+            Error::Unsupported(what) => write!(f, "{} is not supported by this device", what),
+            Error::InvalidDeviceTable(reason) => write!(f, "invalid device table: {}", reason),
+            Error::Io(err) => write!(f, "{}", err),
+            Error::InvalidEepromDump(reason) => write!(f, "invalid eeprom dump: {}", reason),
+            Error::EepromBackupMismatch => {
+                write!(f, "the card's eeprom does not match the backup, refusing to write it")
+            }
+            Error::EepromVerifyFailed => {
+                write!(f, "eeprom contents did not match after writing")
+            }
+            Error::InvalidRecording(reason) => write!(f, "invalid usb recording: {}", reason),
+            Error::CaptureThreadPanicked => write!(f, "the capture thread panicked"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::UsbInit(err)
+            | Error::DeviceOpen(err)
+            | Error::FirmwareUpload(err)
+            | Error::FpgaConfig(err)
+            | Error::TransferFailed(err) => Some(err),
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,88 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use sha2::{Digest, Sha256};
+
+use crate::Error;
+
+/// Environment variable checked by `FirmwareSource::Default` for a firmware directory.
+pub const FIRMWARE_DIR_ENV: &str = "CAPPY3DS_FIRMWARE_DIR";
+
+/// Where backends get their vendor firmware and FPGA bitstreams from.
+///
+/// A directory is laid out like `resources/` in the source tree, one folder per backend,
+/// e.g. `<dir>/Katsukity/firm.bin` and `<dir>/Katsukity/bitstream.bin`.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum FirmwareSource {
+    /// The directory in `CAPPY3DS_FIRMWARE_DIR` if it is set, otherwise the embedded files
+    #[default]
+    Default,
+    /// Files built into the library, only available with the `embedded-firmware` feature
+    Embedded,
+    /// Files in a directory picked by the user
+    Directory(PathBuf),
+}
+
+impl FirmwareSource {
+    /// True if this build carries the vendor files inside the library.
+    pub fn has_embedded() -> bool {
+        cfg!(feature = "embedded-firmware")
+    }
+
+    /// Reads `backend/name`, `embedded` looks the file up in the backend's built in resources.
+    pub(crate) fn load(
+        &self,
+        backend: &str,
+        name: &str,
+        embedded: fn(&str) -> Option<Vec<u8>>,
+    ) -> Result<Vec<u8>, Error> {
+        match self {
+            FirmwareSource::Default => match env::var_os(FIRMWARE_DIR_ENV) {
+                Some(dir) => FirmwareSource::Directory(dir.into()).load(backend, name, embedded),
+                None => FirmwareSource::Embedded.load(backend, name, embedded),
+            },
+            FirmwareSource::Embedded => embedded(name).ok_or_else(|| {
+                Error::FirmwareMissing(format!(
+                    "{}/{} is not built in, set {} or configure a firmware directory",
+                    backend, name, FIRMWARE_DIR_ENV
+                ))
+            }),
+            FirmwareSource::Directory(dir) => {
+                let path = dir.join(backend).join(name);
+                fs::read(&path).map_err(|err| {
+                    Error::FirmwareMissing(format!("{}: {}", path.display(), err))
+                })
+            }
+        }
+    }
+}
+
+/// A firmware or bitstream file that is known to work with a card.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct KnownImage {
+    /// File name inside the backend's resource folder
+    pub name: &'static str,
+    /// Which card or mode this file is for
+    pub variant: &'static str,
+    /// SHA-256 of the whole file, lower case hex
+    pub sha256: &'static str,
+}
+
+/// Checks `data` against the known good digests for `name`, returning the matching variant.
+pub(crate) fn verify_image(
+    name: &str,
+    data: &[u8],
+    known: &[KnownImage],
+) -> Result<&'static str, Error> {
+    let digest = hex::encode(Sha256::digest(data));
+
+    known
+        .iter()
+        .find(|image| image.name == name && image.sha256 == digest)
+        .map(|image| image.variant)
+        .ok_or_else(|| Error::FirmwareDigestMismatch {
+            name: name.to_string(),
+            sha256: digest,
+        })
+}
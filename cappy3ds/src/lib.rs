@@ -1,50 +1,202 @@
+mod capabilities;
 mod capture;
+mod device_info;
+mod eeprom;
+mod error;
+mod firmware;
+mod recording;
+mod session;
+mod watcher;
 
-use capture::{Capture, katsukitty::Katsukity};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use capture::registry;
 
 use bytes::BytesMut;
-use rusb::Context;
+
+pub use capabilities::{AudioFormat, Capabilities, ScreenGeometry};
+pub use capture::bus::{
+    OpenDevice, RusbBus, UsbBus, UsbDescription, UsbDevice, UsbHandle, UsbSelector,
+};
+pub use capture::transfer::TransferConfig;
+pub use capture::transport::{BulkStream, StreamHandler, Transport};
+#[cfg(feature = "test-support")]
+pub use capture::transport::{Exchange, MockTransport};
+pub use capture::devices::{
+    DeviceProfile, DeviceTable, Endpoints, Quirks, UsbId, DEVICE_TABLE_ENV,
+};
+pub use capture::fx2::{FirmwareImage, Segment};
+pub use capture::katsukitty::command::{FpgaCommand, Packet};
+pub use capture::katsukitty::parse::{split_frame, Frame, FrameDamage, LineParser, ParseError};
+pub use capture::katsukitty::replay::{KatsukityReplay, ReplaySpeed};
+#[cfg(feature = "test-support")]
+pub use capture::katsukitty::sim::{SimulatedKatsukity, SIM_LOWER_COLOR, SIM_UPPER_COLOR};
+pub use capture::registry::{backends, BackendEntry};
+pub use capture::{CaptureBackend, ColorDepth, ConnectConfig, DataCallback};
+pub use device_info::DeviceInfo;
+pub use eeprom::{EepromDump, EEPROM_SIZE};
+pub use error::Error;
+pub use firmware::{FirmwareSource, FIRMWARE_DIR_ENV};
+pub use recording::{RecordedTransfer, RecordingReader, RecordingWriter};
+pub use session::{CaptureEvent, CaptureSession};
+pub use watcher::{DeviceEvent, DeviceId, DeviceWatcher};
 
 pub struct Cappy3ds<F> {
-    data_callback: F,
-    usb_context: Option<rusb::Context>,
-    device_handle: Option<rusb::DeviceHandle<rusb::Context>>,
+    data_callback: Arc<Mutex<F>>,
+    bus: Option<Arc<dyn UsbBus>>,
+    device_info: Option<DeviceInfo>,
+    backend: Option<Box<dyn CaptureBackend>>,
+    transfer_config: TransferConfig,
+    connect_config: ConnectConfig,
+    auto_recover: bool,
+    record_to: Option<PathBuf>,
 }
 
 impl<F> Cappy3ds<F>
 where
-    F: FnMut(&[i16], BytesMut, BytesMut),
+    F: FnMut(&[i16], BytesMut, BytesMut) + Send + 'static,
 {
     pub fn new(data_callback: F) -> Self {
         Self {
-            data_callback,
-            usb_context: None,
-            device_handle: None,
+            data_callback: Arc::new(Mutex::new(data_callback)),
+            bus: None,
+            device_info: None,
+            backend: None,
+            transfer_config: TransferConfig::default(),
+            connect_config: ConnectConfig::default(),
+            auto_recover: false,
+            record_to: None,
         }
     }
 
-    pub fn connect(&mut self) {
-        let katsukity = capture::katsukitty::Katsukity::new();
-        
-        match Context::new() {
-            Ok(mut context) => match Katsukity::connect(&mut context) {
-                Ok(handle) => {
-                    self.device_handle = Some(handle);
-                    self.usb_context = Some(context);
-                }
-                Err(err) => {
-                    println!("{}", err);
-                    todo!();
-                }
-            },
-            Err(e) => {
-                println!("could not initialize libusb: {}", e);
-                todo!();
+    /// Sets how `connect` sets up the card and how long it waits before giving up.
+    pub fn set_connect_config(&mut self, config: ConnectConfig) {
+        self.connect_config = config;
+    }
+
+    /// Sets the USB transfer size and queue depth used by the next `start()`.
+    pub fn set_transfer_config(&mut self, config: TransferConfig) {
+        self.transfer_config = config;
+    }
+
+    pub fn connect(&mut self) -> Result<DeviceInfo, Error> {
+        self.connect_with_bus(Arc::new(RusbBus::new()?))
+    }
+
+    /// Like `connect`, but looks for the card on `bus` instead of the machine's USB ports.
+    pub fn connect_with_bus(&mut self, bus: Arc<dyn UsbBus>) -> Result<DeviceInfo, Error> {
+        let (backend, info) = registry::connect_any(&bus, &self.connect_config)?;
+
+        self.bus = Some(bus);
+        self.device_info = Some(info.clone());
+        self.backend = Some(backend);
+
+        Ok(info)
+    }
+
+    /// Plays back a file recorded with `set_recording` instead of connecting to a card.
+    ///
+    /// `start` then feeds the recorded transfers through the usual parser to the data callback
+    /// and the session ends at the end of the file.
+    pub fn replay<P: AsRef<Path>>(&mut self, path: P, speed: ReplaySpeed) -> Result<DeviceInfo, Error> {
+        let replay = KatsukityReplay::open(path, speed)?;
+        let info = replay.device_info().clone();
+
+        self.bus = None;
+        self.device_info = Some(info.clone());
+        self.backend = Some(Box::new(replay));
+
+        Ok(info)
+    }
+
+    /// Name of the backend driving the connected card.
+    pub fn backend_name(&self) -> Option<&'static str> {
+        self.backend.as_ref().map(|backend| backend.name())
+    }
+
+    /// Screen sizes, formats and audio of the connected card, available after a successful `connect`.
+    pub fn capabilities(&self) -> Option<Capabilities> {
+        self.backend.as_ref().map(|backend| backend.capabilities())
+    }
+
+    /// Details of the connected card, available after a successful `connect`.
+    pub fn device_info(&self) -> Option<&DeviceInfo> {
+        self.device_info.as_ref()
+    }
+
+    /// Waits until a supported card is plugged in, or picks one that already is, and connects to it.
+    ///
+    /// Gives up with `Error::DeviceNotFound` if nothing shows up within `timeout`.
+    pub fn connect_on_arrival(&mut self, timeout: Option<Duration>) -> Result<DeviceInfo, Error> {
+        let watcher = DeviceWatcher::new()?;
+
+        loop {
+            watcher
+                .wait_for_arrival(timeout)
+                .ok_or(Error::DeviceNotFound)?;
+
+            match self.connect() {
+                Ok(info) => return Ok(info),
+                // arrivals can show up before the device is ready to be opened
+                Err(Error::DeviceNotFound) => continue,
+                Err(err) => return Err(err),
             }
         }
     }
 
-    pub fn do_capture(self) {
-        capture::katsukitty::do_capture(&mut self.device_handle.unwrap(), self.data_callback);
+    /// Reads the whole EEPROM of the connected card.
+    pub fn backup_eeprom(&self) -> Result<EepromDump, Error> {
+        self.backend()?.read_eeprom()
+    }
+
+    /// Writes a previously saved dump back to the card and verifies it.
+    ///
+    /// This overwrites the card's settings, so `backup` has to be a dump of what the card holds
+    /// right now, e.g. from `backup_eeprom`. The card is read first and nothing is written
+    /// unless it matches, failing with `Error::EepromBackupMismatch`.
+    pub fn restore_eeprom(&self, dump: &EepromDump, backup: &EepromDump) -> Result<(), Error> {
+        self.backend()?.restore_eeprom(dump, backup)
+    }
+
+    fn backend(&self) -> Result<&dyn CaptureBackend, Error> {
+        self.backend.as_deref().ok_or(Error::NotConnected)
+    }
+
+    /// When enabled a session that loses the card waits for it to come back, sets it up again
+    /// and keeps delivering frames to the same callback instead of ending.
+    pub fn set_auto_recover(&mut self, auto_recover: bool) {
+        self.auto_recover = auto_recover;
+    }
+
+    /// Writes the raw USB data of every session started from now on to `path`, replacing
+    /// whatever is there, so it can be looked at or replayed later. `None` stops recording.
+    ///
+    /// Only cards that stream bulk transfers, the Katsukity, can be recorded.
+    pub fn set_recording(&mut self, path: Option<PathBuf>) {
+        self.record_to = path;
+    }
+
+    /// Starts streaming frames to the data callback until the returned session is stopped or dropped.
+    ///
+    /// Only one session can run at a time; once it ends `start` can be called again.
+    pub fn start(&self) -> Result<CaptureSession, Error> {
+        self.backend()?.start(
+            self.data_callback.clone(),
+            self.transfer_config,
+            self.auto_recover,
+            self.record_to.as_deref(),
+        )
+    }
+
+    /// Tells an idle card to stop sending frames, e.g. after a session ended with an error.
+    pub fn stop(&self) -> Result<(), Error> {
+        self.backend()?.stop()
+    }
+
+    /// Captures until the device stops responding.
+    pub fn do_capture(self) -> Result<(), Error> {
+        self.start()?.wait()
     }
 }
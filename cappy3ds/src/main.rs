@@ -1,6 +1,40 @@
-mod capture;
+use std::env;
+use std::process;
+
+use bytes::BytesMut;
+use cappy3ds::{Cappy3ds, ReplaySpeed};
+
+const USAGE: &str = "usage:
+  cappy3ds [--record <file>]
+  cappy3ds --replay <file> [--fast]";
 
 fn main() {
-    //capture::katsukitty::do_capture();
-    //capture::loopy::do_capture();
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    let mut frames = 0u64;
+    let mut cappy3ds = Cappy3ds::new(move |_audio: &[i16], _upper: BytesMut, _lower: BytesMut| {
+        frames += 1;
+        if frames.is_multiple_of(60) {
+            println!("{} frames", frames);
+        }
+    });
+
+    let connected = match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        [] => cappy3ds.connect(),
+        ["--record", path] => {
+            cappy3ds.set_recording(Some(path.into()));
+            cappy3ds.connect()
+        }
+        ["--replay", path] => cappy3ds.replay(path, ReplaySpeed::RealTime),
+        ["--replay", path, "--fast"] => cappy3ds.replay(path, ReplaySpeed::Unthrottled),
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+
+    if let Err(err) = connected.and_then(|_| cappy3ds.do_capture()) {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
 }
@@ -0,0 +1,159 @@
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::{ColorDepth, Error};
+
+// "C3DSRAW" plus a format version
+const MAGIC: &[u8; 8] = b"C3DSRAW\x01";
+
+// timestamp and length in front of every transfer
+const RECORD_HEADER_LEN: usize = 8 + 4;
+// far beyond any transfer size we use, anything bigger is a damaged file
+const MAX_TRANSFER_LEN: usize = 16 * 1024 * 1024;
+
+/// One bulk transfer as it arrived from the card.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordedTransfer {
+    /// Time since the recording started
+    pub timestamp: Duration,
+    pub data: Vec<u8>,
+}
+
+/// Writes every transfer from the data endpoint to a file, for `RecordingReader` and replay.
+///
+/// The file is the magic, the pixel format (0 for RGB565, 1 for RGB888) and the endpoint,
+/// then for every transfer a little endian u64 of microseconds since the start, a little
+/// endian u32 length and the data.
+#[derive(Debug)]
+pub struct RecordingWriter {
+    file: BufWriter<File>,
+    started: Instant,
+}
+
+impl RecordingWriter {
+    /// Creates or truncates `path` and writes the header.
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        color_depth: ColorDepth,
+        endpoint: u8,
+    ) -> Result<Self, Error> {
+        let mut file = BufWriter::new(File::create(path).map_err(Error::Io)?);
+
+        let color_depth = match color_depth {
+            ColorDepth::Rgb565 => 0,
+            ColorDepth::Rgb888 => 1,
+        };
+
+        file.write_all(MAGIC).map_err(Error::Io)?;
+        file.write_all(&[color_depth, endpoint]).map_err(Error::Io)?;
+
+        Ok(Self {
+            file,
+            started: Instant::now(),
+        })
+    }
+
+    /// Appends one transfer, stamped with the time since the file was created.
+    pub fn record(&mut self, data: &[u8]) -> Result<(), Error> {
+        let timestamp = self.started.elapsed().as_micros() as u64;
+
+        self.file
+            .write_all(&timestamp.to_le_bytes())
+            .and_then(|_| self.file.write_all(&(data.len() as u32).to_le_bytes()))
+            .and_then(|_| self.file.write_all(data))
+            .map_err(Error::Io)
+    }
+
+    /// Writes out anything still buffered.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.file.flush().map_err(Error::Io)
+    }
+}
+
+/// Reads back a file written by `RecordingWriter`, one transfer at a time.
+#[derive(Debug)]
+pub struct RecordingReader {
+    file: BufReader<File>,
+    color_depth: ColorDepth,
+    endpoint: u8,
+}
+
+impl RecordingReader {
+    /// Opens `path` and checks its header.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let mut file = BufReader::new(File::open(path).map_err(Error::Io)?);
+
+        let mut header = [0; MAGIC.len() + 2];
+        match file.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                return Err(Error::InvalidRecording("not a usb recording"))
+            }
+            Err(err) => return Err(Error::Io(err)),
+        }
+
+        if &header[..MAGIC.len()] != MAGIC {
+            return Err(Error::InvalidRecording("not a usb recording"));
+        }
+
+        let color_depth = match header[MAGIC.len()] {
+            0 => ColorDepth::Rgb565,
+            1 => ColorDepth::Rgb888,
+            _ => return Err(Error::InvalidRecording("unknown pixel format")),
+        };
+
+        Ok(Self {
+            file,
+            color_depth,
+            endpoint: header[MAGIC.len() + 1],
+        })
+    }
+
+    /// Pixel format the card was sending.
+    pub fn color_depth(&self) -> ColorDepth {
+        self.color_depth
+    }
+
+    /// Endpoint the transfers were read from.
+    pub fn endpoint(&self) -> u8 {
+        self.endpoint
+    }
+
+    /// The next transfer, `None` at the end of the file.
+    ///
+    /// A recording cut short, e.g. because the program crashed, ends at its last whole
+    /// transfer rather than failing.
+    pub fn next_transfer(&mut self) -> Result<Option<RecordedTransfer>, Error> {
+        let mut header = [0; RECORD_HEADER_LEN];
+        if !self.read_or_eof(&mut header)? {
+            return Ok(None);
+        }
+
+        let timestamp = u64::from_le_bytes(header[..8].try_into().unwrap());
+        let len = u32::from_le_bytes(header[8..].try_into().unwrap()) as usize;
+        if len > MAX_TRANSFER_LEN {
+            return Err(Error::InvalidRecording("transfer too large"));
+        }
+
+        let mut data = vec![0; len];
+        if !self.read_or_eof(&mut data)? {
+            return Ok(None);
+        }
+
+        Ok(Some(RecordedTransfer {
+            timestamp: Duration::from_micros(timestamp),
+            data,
+        }))
+    }
+
+    // false if the file ended before `buf` was filled
+    fn read_or_eof(&mut self, buf: &mut [u8]) -> Result<bool, Error> {
+        match self.file.read_exact(buf) {
+            Ok(()) => Ok(true),
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+            Err(err) => Err(Error::Io(err)),
+        }
+    }
+}
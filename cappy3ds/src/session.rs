@@ -0,0 +1,150 @@
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+use std::thread::{self, JoinHandle};
+
+use crate::{Error, FrameDamage};
+
+/// Requests sent from a `CaptureSession` to the thread driving the capture.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Command {
+    Pause,
+    Resume,
+    Stop,
+}
+
+const EVENT_QUEUE_SIZE: usize = 64;
+
+/// Something that happened on the USB side of a running capture.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureEvent {
+    /// A transfer timed out, usually because the console is off or capture is paused
+    Timeout,
+    /// The data endpoint stalled, the halt is cleared and capture carries on
+    Stall,
+    /// The device sent more data than a transfer could hold, the data was dropped
+    Overflow,
+    /// A transfer failed, repeated failures stop the capture
+    TransferError,
+    /// The card was unplugged or reset, capture has stopped
+    Disconnected,
+    /// Auto recovery is waiting for the card to come back
+    Reconnecting,
+    /// The card was set up again and frames are flowing to the same callback
+    Reconnected,
+    /// A frame was delivered with lines missing or repeated, see `FrameDamage`
+    DamagedFrame(FrameDamage),
+}
+
+/// Handle to a running capture.
+///
+/// Capture runs on its own thread until `stop()` is called or the handle is dropped,
+/// after which the device is left idle and ready for another `Cappy3ds::start()`.
+pub struct CaptureSession {
+    commands: Sender<Command>,
+    events: Receiver<CaptureEvent>,
+    worker: Option<JoinHandle<Result<(), Error>>>,
+}
+
+impl CaptureSession {
+    /// Runs `worker` on a new thread. The worker must return once it receives `Command::Stop`.
+    pub(crate) fn spawn<W>(worker: W) -> Self
+    where
+        W: FnOnce(Receiver<Command>, SyncSender<CaptureEvent>) -> Result<(), Error> + Send + 'static,
+    {
+        let (commands, receiver) = mpsc::channel();
+        // events that nobody reads are dropped once this fills up
+        let (sender, events) = mpsc::sync_channel(EVENT_QUEUE_SIZE);
+
+        let worker = thread::Builder::new()
+            .name("cappy3ds capture".to_string())
+            .spawn(move || worker(receiver, sender))
+            .expect("failed to spawn capture thread");
+
+        Self {
+            commands,
+            events,
+            worker: Some(worker),
+        }
+    }
+
+    /// Events reported by the capture thread, in the order they happened.
+    pub fn events(&self) -> &Receiver<CaptureEvent> {
+        &self.events
+    }
+
+    /// Stops the card from sending frames without tearing down the session.
+    pub fn pause(&self) {
+        let _ = self.commands.send(Command::Pause);
+    }
+
+    /// Restarts frame delivery after `pause()`.
+    pub fn resume(&self) {
+        let _ = self.commands.send(Command::Resume);
+    }
+
+    /// Returns false once the capture thread has exited, e.g. because of a transfer error.
+    pub fn is_running(&self) -> bool {
+        match &self.worker {
+            Some(worker) => !worker.is_finished(),
+            None => false,
+        }
+    }
+
+    /// Stops capturing and waits for the device to be released.
+    pub fn stop(mut self) -> Result<(), Error> {
+        self.shutdown()
+    }
+
+    /// Blocks until the capture thread exits on its own.
+    pub fn wait(mut self) -> Result<(), Error> {
+        match self.worker.take() {
+            Some(worker) => join(worker),
+            None => Ok(()),
+        }
+    }
+
+    fn shutdown(&mut self) -> Result<(), Error> {
+        match self.worker.take() {
+            Some(worker) => {
+                let _ = self.commands.send(Command::Stop);
+                join(worker)
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+// the panic has already been reported on the capture thread, only hand back an error
+fn join(worker: JoinHandle<Result<(), Error>>) -> Result<(), Error> {
+    worker.join().unwrap_or(Err(Error::CaptureThreadPanicked))
+}
+
+impl Drop for CaptureSession {
+    fn drop(&mut self) {
+        // nobody is left to read events, so the error can only be logged
+        if let Err(err) = self.shutdown() {
+            log::warn!("capture stopped with error: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn panicking_worker_is_reported_as_an_error() {
+        let session = CaptureSession::spawn(|_, _| panic!("worker failed"));
+        assert!(matches!(session.wait(), Err(Error::CaptureThreadPanicked)));
+
+        let session = CaptureSession::spawn(|commands, _| {
+            commands.recv().unwrap();
+            panic!("worker failed");
+        });
+        assert!(matches!(session.stop(), Err(Error::CaptureThreadPanicked)));
+    }
+
+    #[test]
+    fn dropping_a_panicked_session_does_not_panic() {
+        drop(CaptureSession::spawn(|_, _| panic!("worker failed")));
+    }
+}
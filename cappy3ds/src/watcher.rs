@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use rusb::{Context, Device, Hotplug, HotplugBuilder, UsbContext};
+
+use crate::capture::registry;
+use crate::Error;
+
+/// USB vendor and product id of a capture card.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DeviceId {
+    pub vendor_id: u16,
+    pub product_id: u16,
+}
+
+impl DeviceId {
+    // the device's id, if it is one of `supported`
+    fn of<T: UsbContext>(device: &Device<T>, supported: &[(u16, u16)]) -> Option<Self> {
+        let descriptor = device.device_descriptor().ok()?;
+        let id = (descriptor.vendor_id(), descriptor.product_id());
+
+        if supported.contains(&id) {
+            Some(Self {
+                vendor_id: id.0,
+                product_id: id.1,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// True if any backend knows how to talk to this device.
+    pub fn is_supported(&self) -> Result<bool, Error> {
+        Ok(registry::supported_devices()?.contains(&(self.vendor_id, self.product_id)))
+    }
+}
+
+/// A supported capture card appeared on or disappeared from the bus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceEvent {
+    Arrived(DeviceId),
+    Left(DeviceId),
+}
+
+// how often the fallback rescans the bus
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Watches the bus for capture cards being plugged in and removed.
+///
+/// Uses libusb hotplug notifications where the platform supports them and falls back to
+/// rescanning the bus otherwise. Cards that are already plugged in are reported as arrived
+/// right after the watcher starts.
+pub struct DeviceWatcher {
+    events: Receiver<DeviceEvent>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl DeviceWatcher {
+    pub fn new() -> Result<Self, Error> {
+        let supported = registry::supported_devices()?;
+        let context = Context::new().map_err(Error::UsbInit)?;
+
+        let (sender, events) = mpsc::channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_internal = stop.clone();
+
+        let thread = if rusb::has_hotplug() {
+            let mut builder = HotplugBuilder::new();
+            builder.enumerate(true);
+            let registration = builder
+                .register(&context, Box::new(HotplugSender { sender, supported }))
+                .map_err(Error::UsbInit)?;
+
+            thread::spawn(move || {
+                // keep the callback registered for as long as we are handling events
+                let _registration = registration;
+
+                while !stop_internal.load(Ordering::Relaxed) {
+                    let _ = context.handle_events(Some(POLL_INTERVAL));
+                }
+            })
+        } else {
+            thread::spawn(move || poll_devices(context, sender, supported, stop_internal))
+        };
+
+        Ok(Self {
+            events,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// Arrival and removal events, in the order they were seen.
+    pub fn events(&self) -> &Receiver<DeviceEvent> {
+        &self.events
+    }
+
+    /// Blocks until a supported card arrives, returning `None` if `timeout` runs out first.
+    pub fn wait_for_arrival(&self, timeout: Option<Duration>) -> Option<DeviceId> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+        loop {
+            let event = match deadline {
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    match self.events.recv_timeout(remaining) {
+                        Ok(event) => event,
+                        Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => {
+                            return None
+                        }
+                    }
+                }
+                None => self.events.recv().ok()?,
+            };
+
+            if let DeviceEvent::Arrived(id) = event {
+                return Some(id);
+            }
+        }
+    }
+}
+
+impl Drop for DeviceWatcher {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+struct HotplugSender {
+    sender: Sender<DeviceEvent>,
+    supported: Vec<(u16, u16)>,
+}
+
+impl Hotplug<Context> for HotplugSender {
+    fn device_arrived(&mut self, device: Device<Context>) {
+        if let Some(id) = DeviceId::of(&device, &self.supported) {
+            let _ = self.sender.send(DeviceEvent::Arrived(id));
+        }
+    }
+
+    fn device_left(&mut self, device: Device<Context>) {
+        if let Some(id) = DeviceId::of(&device, &self.supported) {
+            let _ = self.sender.send(DeviceEvent::Left(id));
+        }
+    }
+}
+
+fn poll_devices(
+    context: Context,
+    sender: Sender<DeviceEvent>,
+    supported: Vec<(u16, u16)>,
+    stop: Arc<AtomicBool>,
+) {
+    let mut present = HashMap::<DeviceId, usize>::new();
+
+    while !stop.load(Ordering::Relaxed) {
+        let mut current = HashMap::<DeviceId, usize>::new();
+
+        if let Ok(devices) = context.devices() {
+            for device in devices.iter() {
+                if let Some(id) = DeviceId::of(&device, &supported) {
+                    *current.entry(id).or_insert(0) += 1;
+                }
+            }
+        }
+
+        for (id, &count) in &current {
+            let before = present.get(id).copied().unwrap_or(0);
+            for _ in before..count {
+                let _ = sender.send(DeviceEvent::Arrived(*id));
+            }
+        }
+
+        for (id, &count) in &present {
+            let now = current.get(id).copied().unwrap_or(0);
+            for _ in now..count {
+                let _ = sender.send(DeviceEvent::Left(*id));
+            }
+        }
+
+        present = current;
+
+        thread::sleep(POLL_INTERVAL);
+    }
+}
@@ -0,0 +1,328 @@
+use std::fs;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bytes::BytesMut;
+use cappy3ds::{
+    CaptureEvent, CaptureSession, Cappy3ds, ColorDepth, ConnectConfig, Error, FirmwareImage,
+    FirmwareSource, RecordingReader, ReplaySpeed, SimulatedKatsukity, UsbBus, UsbSelector,
+    SIM_LOWER_COLOR, SIM_UPPER_COLOR,
+};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+type Callback = Box<dyn FnMut(&[i16], BytesMut, BytesMut) + Send>;
+
+struct Frame {
+    upper: BytesMut,
+    lower: BytesMut,
+}
+
+// a firmware directory with a made up bitstream, the simulator doesn't look at its contents
+fn firmware_dir(test: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("cappy3ds-sim-{}-{}", test, std::process::id()));
+    fs::create_dir_all(dir.join("Katsukity")).unwrap();
+
+    let bitstream: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+    fs::write(dir.join("Katsukity").join("bitstream.bin"), bitstream).unwrap();
+
+    dir
+}
+
+fn connect_config(test: &str) -> ConnectConfig {
+    ConnectConfig {
+        reenumeration_timeout: Duration::from_secs(1),
+        fpga_timeout: Duration::from_secs(1),
+        detect_timeout: Duration::from_millis(100),
+        firmware_source: FirmwareSource::Directory(firmware_dir(test)),
+        // an LJMP to itself, enough for the simulated boot ROM
+        fx2_firmware: Some(FirmwareImage::from_binary(&[0x02, 0x00, 0x00], 0).unwrap()),
+        allow_unknown_firmware: true,
+        ..ConnectConfig::default()
+    }
+}
+
+fn capture(config: ConnectConfig) -> (Cappy3ds<Callback>, Receiver<Frame>) {
+    // frames that nobody picks up in time are dropped, only the latest few matter
+    let (sender, frames) = mpsc::sync_channel(2);
+
+    let mut cappy = Cappy3ds::new(Box::new(move |_audio: &[i16], upper, lower| {
+        let _ = sender.try_send(Frame { upper, lower });
+    }) as Callback);
+    cappy.set_connect_config(config);
+
+    (cappy, frames)
+}
+
+fn next_frame(frames: &Receiver<Frame>) -> Frame {
+    frames.recv_timeout(TIMEOUT).expect("no frame arrived")
+}
+
+// skips frames that were already queued, e.g. from before a fault was injected
+fn fresh_frame(frames: &Receiver<Frame>) -> Frame {
+    while frames.try_recv().is_ok() {}
+    next_frame(frames)
+}
+
+fn assert_test_pattern(frame: &Frame) {
+    assert_eq!(frame.upper.len(), 400 * 240 * 4);
+    assert_eq!(frame.lower.len(), 320 * 240 * 4);
+    assert!(frame.upper.chunks(4).all(|pixel| pixel == SIM_UPPER_COLOR));
+    assert!(frame.lower.chunks(4).all(|pixel| pixel == SIM_LOWER_COLOR));
+}
+
+fn wait_for_event(session: &CaptureSession, expected: CaptureEvent) {
+    wait_for(session, |event| event == expected, &format!("{:?}", expected));
+}
+
+fn wait_for<P: Fn(CaptureEvent) -> bool>(session: &CaptureSession, wanted: P, what: &str) {
+    let deadline = Instant::now() + TIMEOUT;
+
+    while Instant::now() < deadline {
+        if let Ok(event) = session.events().recv_timeout(deadline - Instant::now()) {
+            if wanted(event) {
+                return;
+            }
+        }
+    }
+
+    panic!("no {} event", what);
+}
+
+#[test]
+fn connect_uploads_firmware_and_streams_frames() {
+    let sim = SimulatedKatsukity::new();
+    let (mut cappy, frames) = capture(connect_config("stream"));
+
+    let info = cappy.connect_with_bus(Arc::new(sim.clone())).unwrap();
+    assert_eq!(info.model, "Simulated Katsukity");
+    assert_eq!(info.eeprom, sim.eeprom());
+    assert_eq!(sim.usb_id(), Some((0x0752, 0xf2c0)));
+    assert_eq!(sim.firmware_uploads(), 1);
+    assert_eq!(sim.color_depth(), Some(ColorDepth::Rgb565));
+    // the audio slice only carries line headers, so no sound format is claimed
+    assert_eq!(cappy.capabilities().unwrap().audio, None);
+
+    let session = cappy.start().unwrap();
+    assert!(sim.is_streaming());
+
+    for _ in 0..3 {
+        assert_test_pattern(&next_frame(&frames));
+    }
+
+    session.stop().unwrap();
+    assert!(!sim.is_streaming());
+}
+
+#[test]
+fn listed_devices_open_only_while_they_are_still_there() {
+    let sim = SimulatedKatsukity::new();
+
+    let [fx2] = sim.devices().unwrap()[..] else {
+        panic!("expected one device");
+    };
+    assert_eq!((fx2.vendor_id, fx2.product_id), (0x0752, 0x8613));
+    assert!(sim.open(UsbSelector::Device(fx2)).unwrap().is_some());
+
+    // flashing the FX2 makes it re-enumerate at a new address
+    let (mut cappy, _) = capture(connect_config("listing"));
+    cappy.connect_with_bus(Arc::new(sim.clone())).unwrap();
+
+    let [card] = sim.devices().unwrap()[..] else {
+        panic!("expected one device");
+    };
+    assert_ne!(card.address, fx2.address);
+    assert!(sim.open(UsbSelector::Device(fx2)).unwrap().is_none());
+    assert!(sim.open(UsbSelector::Device(card)).unwrap().is_some());
+    assert!(sim
+        .open(UsbSelector::id(card.vendor_id, card.product_id))
+        .unwrap()
+        .is_some());
+}
+
+#[test]
+fn rgb888_reconfigures_the_fpga() {
+    let sim = SimulatedKatsukity::new();
+    let (mut cappy, frames) = capture(ConnectConfig {
+        color_depth: Some(ColorDepth::Rgb888),
+        ..connect_config("rgb888")
+    });
+
+    cappy.connect_with_bus(Arc::new(sim.clone())).unwrap();
+    assert_eq!(sim.color_depth(), Some(ColorDepth::Rgb888));
+
+    let session = cappy.start().unwrap();
+    assert_test_pattern(&next_frame(&frames));
+    session.stop().unwrap();
+}
+
+#[test]
+fn configured_card_is_picked_up_without_reflashing() {
+    let sim = SimulatedKatsukity::new();
+
+    let (mut first, _) = capture(connect_config("reuse"));
+    first.connect_with_bus(Arc::new(sim.clone())).unwrap();
+    drop(first);
+
+    let (mut second, frames) = capture(connect_config("reuse"));
+    second.connect_with_bus(Arc::new(sim.clone())).unwrap();
+    assert_eq!(sim.firmware_uploads(), 1);
+
+    let session = second.start().unwrap();
+    assert_test_pattern(&next_frame(&frames));
+    session.stop().unwrap();
+}
+
+#[test]
+fn eeprom_restore_needs_a_backup_of_the_card() {
+    let original = SimulatedKatsukity::with_eeprom(b"first card".to_vec());
+    let (mut cappy, _) = capture(connect_config("eeprom"));
+    cappy.connect_with_bus(Arc::new(original.clone())).unwrap();
+    let dump = cappy.backup_eeprom().unwrap();
+    assert_eq!(dump.data(), &original.eeprom()[..]);
+
+    let other = SimulatedKatsukity::with_eeprom(b"second card".to_vec());
+    let (mut cappy, _) = capture(connect_config("eeprom"));
+    cappy.connect_with_bus(Arc::new(other.clone())).unwrap();
+    let before = other.eeprom();
+
+    // a backup of some other card doesn't count
+    assert!(matches!(
+        cappy.restore_eeprom(&dump, &dump),
+        Err(Error::EepromBackupMismatch)
+    ));
+    assert_eq!(other.eeprom(), before);
+
+    let backup = cappy.backup_eeprom().unwrap();
+    cappy.restore_eeprom(&dump, &backup).unwrap();
+    assert_eq!(other.eeprom(), original.eeprom());
+}
+
+#[test]
+fn eeprom_restore_checks_the_write_took() {
+    let (mut cappy, _) = capture(connect_config("eeprom"));
+    cappy
+        .connect_with_bus(Arc::new(SimulatedKatsukity::with_eeprom(b"first card".to_vec())))
+        .unwrap();
+    let dump = cappy.backup_eeprom().unwrap();
+
+    let sim = SimulatedKatsukity::with_eeprom(b"second card".to_vec());
+    let (mut cappy, _) = capture(connect_config("eeprom"));
+    cappy.connect_with_bus(Arc::new(sim.clone())).unwrap();
+    let backup = cappy.backup_eeprom().unwrap();
+
+    sim.protect_eeprom();
+    assert!(matches!(
+        cappy.restore_eeprom(&dump, &backup),
+        Err(Error::EepromVerifyFailed)
+    ));
+    assert_eq!(sim.eeprom(), backup.data());
+}
+
+#[test]
+fn capture_survives_stalls_and_lost_transfers() {
+    let sim = SimulatedKatsukity::new();
+    let (mut cappy, frames) = capture(connect_config("faults"));
+    cappy.connect_with_bus(Arc::new(sim.clone())).unwrap();
+
+    let session = cappy.start().unwrap();
+    next_frame(&frames);
+
+    sim.stall();
+    wait_for_event(&session, CaptureEvent::Stall);
+
+    // frames around the hole come out with lines missing, the ones after it are whole again
+    sim.drop_transfers(3);
+    wait_for(
+        &session,
+        |event| matches!(event, CaptureEvent::DamagedFrame(damage) if damage.missing_lines > 0),
+        "DamagedFrame",
+    );
+    let sent = sim.frames_sent();
+    while sim.frames_sent() < sent + 5 {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert_test_pattern(&fresh_frame(&frames));
+
+    session.stop().unwrap();
+}
+
+#[test]
+fn unplugging_ends_the_session() {
+    let sim = SimulatedKatsukity::new();
+    let (mut cappy, frames) = capture(connect_config("unplug"));
+    cappy.connect_with_bus(Arc::new(sim.clone())).unwrap();
+
+    let session = cappy.start().unwrap();
+    next_frame(&frames);
+
+    sim.unplug();
+    wait_for_event(&session, CaptureEvent::Disconnected);
+    assert!(matches!(session.wait(), Err(Error::Disconnected)));
+}
+
+#[test]
+fn auto_recover_sets_a_replugged_card_up_again() {
+    let sim = SimulatedKatsukity::new();
+    let (mut cappy, frames) = capture(connect_config("recover"));
+    cappy.set_auto_recover(true);
+    cappy.connect_with_bus(Arc::new(sim.clone())).unwrap();
+
+    let session = cappy.start().unwrap();
+    next_frame(&frames);
+
+    sim.unplug();
+    wait_for_event(&session, CaptureEvent::Reconnecting);
+
+    sim.plug_in();
+    wait_for_event(&session, CaptureEvent::Reconnected);
+    assert_eq!(sim.firmware_uploads(), 2);
+    assert_test_pattern(&fresh_frame(&frames));
+
+    session.stop().unwrap();
+}
+
+#[test]
+fn recorded_session_replays_through_the_parser() {
+    let sim = SimulatedKatsukity::new();
+    let recording = firmware_dir("record").join("session.raw");
+
+    let (mut cappy, frames) = capture(connect_config("record"));
+    cappy.set_recording(Some(recording.clone()));
+    cappy.connect_with_bus(Arc::new(sim.clone())).unwrap();
+
+    let session = cappy.start().unwrap();
+    for _ in 0..3 {
+        next_frame(&frames);
+    }
+    session.stop().unwrap();
+
+    let mut reader = RecordingReader::open(&recording).unwrap();
+    assert_eq!(reader.color_depth(), ColorDepth::Rgb565);
+    assert_eq!(reader.endpoint(), 0x82);
+
+    let mut last = Duration::ZERO;
+    let mut transfers = 0;
+    while let Some(transfer) = reader.next_transfer().unwrap() {
+        assert!(transfer.timestamp >= last);
+        assert!(!transfer.data.is_empty());
+        last = transfer.timestamp;
+        transfers += 1;
+    }
+    assert!(transfers > 0);
+
+    let (mut replay, frames) = capture(connect_config("record"));
+    let info = replay.replay(&recording, ReplaySpeed::Unthrottled).unwrap();
+    assert_eq!(info.model, "session.raw");
+    assert_eq!(
+        replay.capabilities().unwrap().color_depths,
+        &[ColorDepth::Rgb565]
+    );
+
+    let session = replay.start().unwrap();
+    assert_test_pattern(&next_frame(&frames));
+    // the session ends on its own at the end of the file
+    session.wait().unwrap();
+}
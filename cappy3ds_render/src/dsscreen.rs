@@ -3,7 +3,8 @@ use bytes::BytesMut;
 use glam::Mat4;
 use wgpu::{util::DeviceExt, Extent3d, TextureFormat};
 
-const VERTICES: &[Vertex] = &[
+// 3DS screens arrive a column at a time, so the texture is drawn turned 90 degrees
+const ROTATED_VERTICES: &[Vertex] = &[
     Vertex {
         position: [0.0, 0.0, 0.0],
         tex_coords: [0.0, 0.0],
@@ -22,6 +23,25 @@ const VERTICES: &[Vertex] = &[
     },
 ];
 
+const UPRIGHT_VERTICES: &[Vertex] = &[
+    Vertex {
+        position: [0.0, 0.0, 0.0],
+        tex_coords: [0.0, 1.0],
+    },
+    Vertex {
+        position: [1.0, 0.0, 0.0],
+        tex_coords: [1.0, 1.0],
+    },
+    Vertex {
+        position: [0.0, 1.0, 0.0],
+        tex_coords: [0.0, 0.0],
+    },
+    Vertex {
+        position: [1.0, 1.0, 0.0],
+        tex_coords: [1.0, 0.0],
+    },
+];
+
 const INDICES: &[u16] = &[0, 1, 2, 2, 1, 3];
 
 pub struct DSScreen {
@@ -36,6 +56,7 @@ pub struct DSScreen {
     buffer: BytesMut,
     pos_x: u32,
     pos_y: u32,
+    rotated: bool,
     transform_buffer: wgpu::Buffer,
 }
 
@@ -45,11 +66,18 @@ impl DSScreen {
         texture_format: TextureFormat,
         width: u32,
         height: u32,
+        rotated: bool,
         placeholderImageBytes: &[u8],
     ) -> Self {
+        let vertices = if rotated {
+            ROTATED_VERTICES
+        } else {
+            UPRIGHT_VERTICES
+        };
+
         let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("Vertex Buffer"),
-            contents: bytemuck::cast_slice(VERTICES),
+            contents: bytemuck::cast_slice(vertices),
             usage: wgpu::BufferUsages::VERTEX,
         });
 
@@ -234,6 +262,7 @@ impl DSScreen {
             buffer,
             pos_x: 0,
             pos_y: 0,
+            rotated,
             transform_buffer: uniform_buf,
         }
     }
@@ -275,8 +304,18 @@ impl DSScreen {
         queue.write_buffer(&self.transform_buffer, 0, bytemuck::cast_slice(mx_ref));
     }
 
+    /// Width and height on screen, with the column order of rotated screens undone.
+    pub fn display_size(&self) -> (u32, u32) {
+        if self.rotated {
+            (self.texture_size.height, self.texture_size.width)
+        } else {
+            (self.texture_size.width, self.texture_size.height)
+        }
+    }
+
     fn get_matrix(&self) -> glam::Mat4 {
-        generate_matrix(self.texture_size.width, self.texture_size.height, self.pos_x, self.pos_y)
+        let (width, height) = self.display_size();
+        generate_matrix(width, height, self.pos_x, self.pos_y)
     }
 
     pub fn render(&self, encoder: &mut wgpu::CommandEncoder, render_target: &wgpu::TextureView) {
@@ -312,15 +351,15 @@ fn generate_matrix(width: u32, height: u32, x: u32, y: u32) -> glam::Mat4 {
 
     let mx_total = glam::Mat4::orthographic_rh(0.0, SCENE_WIDTH as f32, 0.0, SCENE_HEIGHT as f32, 0.0, 100.0);
 
-    // todo: fix the verticies so our quad isn't rotated or whatever, it is going to kill us later
-    let y = SCENE_HEIGHT - width - y;//y + height;//SCENE_HEIGHT - height - y;
+    // width and height are the size on screen, the vertices take care of rotation
+    let y = SCENE_HEIGHT - height - y;
 
     let translate = glam::Mat4::from_translation(glam::Vec3 {
         x: x as f32,
         y: y as f32,
         z: 0.0,
     });
-    let scale = glam::Mat4::from_scale(glam::Vec3::new(height as f32, width as f32, 1.0));
+    let scale = glam::Mat4::from_scale(glam::Vec3::new(width as f32, height as f32, 1.0));
 
     mx_total * translate * scale
 }
\ No newline at end of file
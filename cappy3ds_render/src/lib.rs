@@ -1,20 +1,26 @@
 use bytes::BytesMut;
+use cappy3ds::{Capabilities, ReplaySpeed};
 use futures::executor;
 use raw_window_handle::{
     AppKitDisplayHandle, AppKitWindowHandle, HasRawDisplayHandle, HasRawWindowHandle,
     RawDisplayHandle, RawWindowHandle, WindowsDisplayHandle,
 };
+use std::env;
 use std::ffi;
+use std::sync::{Arc, Mutex};
 
 
 mod dsscreen;
 mod primitive;
 mod render;
 
-use std::thread;
+use std::{thread, time};
 
 pub use render::State;
 
+// set to a file from `Cappy3ds::set_recording` to run without a console plugged in
+const REPLAY_ENV: &str = "CAPPY3DS_REPLAY";
+
 #[no_mangle]
 #[cfg(target_os = "windows")]
 pub extern "C" fn send_swap_chain_panel(swap_chain_panel: *mut ffi::c_void) {
@@ -47,19 +53,27 @@ pub fn send_raw_window<
     let res = State::new(&window);
     let v = executor::block_on(res);
 
-    let mut heheh = Box::new(v);
+    let heheh = Box::new(v);
 
     heheh.render();
 
-    let thread_join_handle = thread::spawn(move || loop {
-        trash_code(heheh.as_mut());
-    });
+    let state = Arc::new(Mutex::new(heheh));
+
+    let thread_join_handle = thread::spawn(move || trash_code(state));
 }
 
-fn trash_code(v: &mut State) {
+fn trash_code(state: Arc<Mutex<Box<State>>>) {
+    // filled in once we know which card is plugged in
+    let capabilities = Arc::new(Mutex::new(None::<Capabilities>));
+
+    let frame_state = state.clone();
+    let frame_capabilities = capabilities.clone();
     let mut cappy3ds = cappy3ds::Cappy3ds::new(
-        |audio: &[i16], upper_buffer: BytesMut, lower_buffer: BytesMut| {
-            if upper_buffer.len() >= 288000 {
+        move |audio: &[i16], upper_buffer: BytesMut, lower_buffer: BytesMut| {
+            // the textures are sized once connect tells us the screens
+            if frame_capabilities.lock().unwrap().is_some() {
+                let mut v = frame_state.lock().unwrap();
+
                 v.write_texture(&upper_buffer, &lower_buffer);
 
                 v.render();
@@ -67,9 +81,37 @@ fn trash_code(v: &mut State) {
         },
     );
 
-    cappy3ds.connect();
-
-    cappy3ds.do_capture();
+    cappy3ds.set_auto_recover(true);
+
+    // back off while connecting keeps failing, e.g. on a card we aren't allowed to open
+    let min_retry_delay = time::Duration::from_secs(1);
+    let max_retry_delay = time::Duration::from_secs(30);
+    let mut retry_delay = min_retry_delay;
+
+    loop {
+        let connected = match env::var_os(REPLAY_ENV) {
+            Some(path) => cappy3ds.replay(path, ReplaySpeed::RealTime),
+            // light up as soon as a card is plugged in
+            None => cappy3ds.connect_on_arrival(None),
+        };
+
+        if let Err(err) = connected {
+            eprintln!("{}", err);
+            thread::sleep(retry_delay);
+            retry_delay = (retry_delay * 2).min(max_retry_delay);
+            continue;
+        }
+        retry_delay = min_retry_delay;
+
+        if let Some(connected) = cappy3ds.capabilities() {
+            state.lock().unwrap().configure(&connected);
+            *capabilities.lock().unwrap() = Some(connected);
+        }
+
+        if let Err(err) = cappy3ds.start().and_then(|session| session.wait()) {
+            eprintln!("{}", err);
+        }
+    }
 }
 
 #[cfg(target_os = "macos")]
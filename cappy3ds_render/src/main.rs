@@ -1,7 +1,8 @@
 use bytes::BytesMut;
 use cappy3ds;
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use image::{ImageBuffer, Rgb};
+use image::{ImageBuffer, Rgba};
+use std::sync::{Arc, Mutex};
 use ringbuf::HeapRb;
 
 fn main() {
@@ -44,24 +45,41 @@ fn main() {
         .unwrap();
     println!("Successfully built streams.");
 
+    // filled in after connect so the callback knows what a whole frame looks like
+    let capabilities = Arc::new(Mutex::new(None::<cappy3ds::Capabilities>));
+    let frame_capabilities = capabilities.clone();
+
     let mut cappy3ds = cappy3ds::Cappy3ds::new(
-        |audio: &[i16], upper_buffer: BytesMut, lower_buffer: BytesMut| {
+        move |audio: &[i16], upper_buffer: BytesMut, lower_buffer: BytesMut| {
             print!("{:?}\n", upper_buffer.len());
 
-            if upper_buffer.len() >= 288000 {
+            let Some(capabilities) = frame_capabilities.lock().unwrap().clone() else {
+                return;
+            };
+            let upper = capabilities.upper_screen;
+            let lower = capabilities.lower_screen;
+
+            if upper_buffer.len() >= upper.buffer_len() {
                 let found_frames = "wow";
 
                 // print lower image
-                let result = ImageBuffer::<Rgb<u8>, _>::from_raw(240, 320, lower_buffer);
+                let result =
+                    ImageBuffer::<Rgba<u8>, _>::from_raw(lower.width, lower.height, lower_buffer);
                 if let Some(image) = result {
-                    image.save(format!("./img_out/lower_{}.png", found_frames));
+                    let path = format!("./img_out/lower_{}.png", found_frames);
+                    if let Err(err) = image.save(&path) {
+                        eprintln!("failed to save {}: {}", path, err);
+                    }
                 }
 
                 // print upper image
-                let result: Option<ImageBuffer<Rgb<u8>, BytesMut>> =
-                    ImageBuffer::<Rgb<u8>, _>::from_raw(240, 400, upper_buffer);
+                let result: Option<ImageBuffer<Rgba<u8>, BytesMut>> =
+                    ImageBuffer::<Rgba<u8>, _>::from_raw(upper.width, upper.height, upper_buffer);
                 if let Some(image) = result {
-                    image.save(format!("./img_out/upper_{}.png", found_frames));
+                    let path = format!("./img_out/upper_{}.png", found_frames);
+                    if let Err(err) = image.save(&path) {
+                        eprintln!("failed to save {}: {}", path, err);
+                    }
                 }
 
                 panic!("LOL");
@@ -73,11 +91,17 @@ fn main() {
         },
     );
 
-    cappy3ds.connect();
+    if let Err(err) = cappy3ds.connect() {
+        eprintln!("{}", err);
+        return;
+    }
+    *capabilities.lock().unwrap() = cappy3ds.capabilities();
 
     //output_stream.play();
 
-    cappy3ds.do_capture();
+    if let Err(err) = cappy3ds.do_capture() {
+        eprintln!("{}", err);
+    }
 }
 
 fn err_fn(err: cpal::StreamError) {
@@ -1,6 +1,8 @@
 use bytes::BytesMut;
 use wgpu::util::DeviceExt;
 
+use cappy3ds::{Capabilities, ScreenGeometry};
+
 use crate::dsscreen::DSScreen;
 
 const SCENE_WIDTH: u32 = 1270;
@@ -18,6 +20,7 @@ pub struct State {
     // it gets dropped after it as the surface contains
     // unsafe references to the window's resources.
     //window: Window,
+    surface_format: wgpu::TextureFormat,
     ds_screen_upper: DSScreen,
     ds_screen_lower: DSScreen,
 }
@@ -126,6 +129,7 @@ impl State {
             surface_format,
             240,
             400,
+            true,
             diffuse_rgba.as_raw().as_slice(),
         );
         ds_screen_upper.update_textures(&queue);
@@ -139,6 +143,7 @@ impl State {
             surface_format,
             240,
             320,
+            true,
             diffuse_rgba.as_raw().as_slice(),
         );
         ds_screen_lower.update_textures(&queue);
@@ -148,11 +153,46 @@ impl State {
             surface,
             device,
             queue,
+            surface_format,
             ds_screen_upper,
             ds_screen_lower,
         }
     }
 
+    /// Resizes both screens to what the connected card sends and lays them out, lower
+    /// screen centered under the upper one.
+    pub fn configure(&mut self, capabilities: &Capabilities) {
+        self.ds_screen_upper = self.create_screen(&capabilities.upper_screen);
+        self.ds_screen_lower = self.create_screen(&capabilities.lower_screen);
+
+        let (upper_width, upper_height) = self.ds_screen_upper.display_size();
+        let (lower_width, _) = self.ds_screen_lower.display_size();
+
+        self.ds_screen_upper.set_position(&self.queue, 0, 0);
+        self.ds_screen_lower.set_position(
+            &self.queue,
+            upper_width.saturating_sub(lower_width) / 2,
+            upper_height,
+        );
+    }
+
+    fn create_screen(&self, geometry: &ScreenGeometry) -> DSScreen {
+        // black until the first frame arrives
+        let placeholder = vec![0; geometry.buffer_len()];
+
+        let screen = DSScreen::new(
+            &self.device,
+            self.surface_format,
+            geometry.width,
+            geometry.height,
+            geometry.rotated,
+            &placeholder,
+        );
+        screen.update_textures(&self.queue);
+
+        screen
+    }
+
     pub fn render(&self) -> Result<(), wgpu::SurfaceError> {
         let output = self.surface.get_current_texture()?;
 